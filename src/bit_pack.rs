@@ -4,7 +4,11 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
-use crate::token::Token::{self, *};
+use crate::token::{
+    LexError, Lexeme, Pos, Remainder, Span,
+    Token::{self, *},
+};
+use std::borrow::Cow;
 
 pub struct BitLexer<'a> {
     src: &'a [u8],
@@ -40,6 +44,51 @@ impl<'a> BitLexer<'a> {
         }
         Some(bit)
     }
+
+    /// Position of the next bit. Columns count bits, not characters.
+    #[inline]
+    #[must_use]
+    fn pos(&self) -> Pos {
+        Pos {
+            offset: self.i,
+            line: 1,
+            col: self.i * 8 + (7 - self.bit as usize) + 1,
+        }
+    }
+
+    /// Adapts the tokens into lexemes, so they can be parsed.
+    #[inline]
+    #[must_use]
+    pub const fn lexemes(self) -> BitLexemes<'a> {
+        BitLexemes(self)
+    }
+}
+
+/// Tokens of bit-packed source as lexemes, which have no comments or
+/// text.
+pub struct BitLexemes<'a>(BitLexer<'a>);
+
+impl<'a> Iterator for BitLexemes<'a> {
+    type Item = Result<Lexeme<'a>, LexError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let start = self.0.pos();
+        let tok = self.0.next()?;
+        Some(Ok(Lexeme {
+            tok,
+            span: Span::new(start, self.0.pos()),
+            comment: Cow::Borrowed(&[]),
+            text: Cow::Borrowed(&[]),
+        }))
+    }
+}
+
+/// Bits after the last token are padding, not source text.
+impl Remainder for BitLexemes<'_> {
+    #[inline]
+    fn remainder(&mut self) -> Result<Vec<u8>, LexError> {
+        Ok(Vec::new())
+    }
 }
 
 impl<'a> Iterator for BitLexer<'a> {
@@ -59,19 +108,108 @@ impl<'a> Iterator for BitLexer<'a> {
     }
 }
 
+/// Packs tokens into bits, with S as 0, T as 10, and L as 11. When the
+/// final token ends with a 0 bit, a 1 marker bit is appended, so that it
+/// is not ignored as a trailing zero.
+pub struct BitWriter {
+    buf: Vec<u8>,
+    bit: u8,
+    last: Option<Token>,
+}
+
+impl BitWriter {
+    #[inline]
+    #[must_use]
+    pub const fn new() -> Self {
+        BitWriter {
+            buf: Vec::new(),
+            bit: 0,
+            last: None,
+        }
+    }
+
+    pub fn push_bit(&mut self, bit: bool) {
+        if self.bit == 0 {
+            self.buf.push(0);
+            self.bit = 8;
+        }
+        self.bit -= 1;
+        if bit {
+            *self.buf.last_mut().unwrap() |= 1 << self.bit;
+        }
+    }
+
+    pub fn push(&mut self, tok: Token) {
+        match tok {
+            S => self.push_bit(false),
+            T => {
+                self.push_bit(true);
+                self.push_bit(false);
+            }
+            L => {
+                self.push_bit(true);
+                self.push_bit(true);
+            }
+        }
+        self.last = Some(tok);
+    }
+
+    #[must_use]
+    pub fn finish(mut self) -> Vec<u8> {
+        if matches!(self.last, Some(S | T)) {
+            self.push_bit(true); // marker bit
+        }
+        self.buf
+    }
+}
+
+impl Extend<Token> for BitWriter {
+    #[inline]
+    fn extend<I: IntoIterator<Item = Token>>(&mut self, iter: I) {
+        iter.into_iter().for_each(|tok| self.push(tok));
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
     use crate::token::test::TUTORIAL_TOKENS;
 
+    const TUTORIAL_WSX: [u8; 19] = [
+        0b00010111, 0b10001000, 0b00101011, 0b01101011, 0b01000010, 0b01001110, 0b11000001,
+        0b01110000, 0b01100001, 0b00101011, 0b10001011, 0b10001000, 0b01001011, 0b11011010,
+        0b00001010, 0b11110001, 0b00001001, 0b01101111, 0b11111100,
+    ];
+
     #[test]
     fn bit_lex_tutorial() {
-        let src = [
-            0b00010111, 0b10001000, 0b00101011, 0b01101011, 0b01000010, 0b01001110, 0b11000001,
-            0b01110000, 0b01100001, 0b00101011, 0b10001011, 0b10001000, 0b01001011, 0b11011010,
-            0b00001010, 0b11110001, 0b00001001, 0b01101111, 0b11111100,
-        ];
-        let tokens = BitLexer::new(&src).collect::<Vec<_>>();
+        let tokens = BitLexer::new(&TUTORIAL_WSX).collect::<Vec<_>>();
         assert_eq!(tokens, TUTORIAL_TOKENS);
     }
+
+    #[test]
+    fn bit_write_tutorial() {
+        let mut w = BitWriter::new();
+        w.extend(TUTORIAL_TOKENS);
+        assert_eq!(w.finish(), TUTORIAL_WSX);
+    }
+
+    #[test]
+    fn bit_round_trip() {
+        let tests: [&[Token]; 7] = [
+            &[],
+            &[S],
+            &[T],
+            &[L],
+            &[L, S, S, S, S, S, S],
+            &[S; 8],
+            &[T; 4],
+        ];
+        for toks in tests {
+            let mut w = BitWriter::new();
+            w.extend(toks.iter().copied());
+            let src = w.finish();
+            assert_eq!(BitLexer::new(&src).collect::<Vec<_>>(), toks);
+        }
+    }
 }
@@ -0,0 +1,172 @@
+// Copyright (c) 2021 Andrew Archibald
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use crate::emit::Emitter;
+use crate::syntax::{Inst, ParseError, ParseOptions, Parser};
+use crate::token::{LexError, Lexeme, Remainder, Token};
+use std::{
+    io::{self, Write},
+    mem,
+};
+
+/// Lossless concrete syntax tree, which preserves every byte of the
+/// source, including comments, line endings, and the leading zeros of
+/// numbers and labels.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cst {
+    pub insts: Vec<InstNode>,
+    /// Comment text after the last token, or source that could not be
+    /// lexed
+    pub trailing: Vec<u8>,
+}
+
+/// Instruction with its tokens, or the syntax error where parsing
+/// stopped with its tokens and all tokens after it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InstNode {
+    pub inst: Result<Inst, ParseError>,
+    pub toks: Vec<TokenNode>,
+}
+
+/// Token with its exact text and the comment text preceding it. Tokens
+/// of bit-packed source have no text.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TokenNode {
+    pub tok: Token,
+    pub text: Vec<u8>,
+    pub comment: Vec<u8>,
+}
+
+impl Cst {
+    /// Parses the lexemes into a tree, then reads the source remaining
+    /// after the last token.
+    pub fn parse<'a, L>(lex: L, opts: ParseOptions) -> Result<Self, LexError>
+    where
+        L: Iterator<Item = Result<Lexeme<'a>, LexError>> + Remainder,
+    {
+        let mut p = Parser::with_options(
+            Recorder {
+                lex,
+                lexemes: Vec::new(),
+            },
+            opts,
+        );
+        let mut insts: Vec<InstNode> = Vec::new();
+        while let Some(inst) = p.next() {
+            insts.push(InstNode {
+                inst: inst.map(|inst| inst.inst),
+                toks: p.lexer_mut().take_nodes(),
+            });
+        }
+        let rec = p.lexer_mut();
+        // Tokens after a syntax error cannot be parsed, so they are kept
+        // with the error
+        while let Some(Ok(_)) = rec.next() {}
+        if let Some(last) = insts.last_mut() {
+            last.toks.extend(rec.take_nodes());
+        }
+        Ok(Cst {
+            insts,
+            trailing: rec.lex.remainder()?,
+        })
+    }
+
+    pub fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        for inst in &self.insts {
+            for tok in &inst.toks {
+                w.write_all(&tok.comment)?;
+                w.write_all(&tok.text)?;
+            }
+        }
+        w.write_all(&self.trailing)
+    }
+
+    /// Writes the tokens with the mapping of the emitter. Comments are
+    /// kept, except for patterns that would be lexed as tokens.
+    pub fn emit(&self, e: &mut Emitter) {
+        for inst in &self.insts {
+            for tok in &inst.toks {
+                e.push_comment(&tok.comment);
+                e.push_token(tok.tok);
+            }
+        }
+        e.push_comment(&self.trailing);
+    }
+}
+
+/// Records the lexemes consumed by the parser.
+struct Recorder<'a, L> {
+    lex: L,
+    lexemes: Vec<Lexeme<'a>>,
+}
+
+impl<L> Recorder<'_, L> {
+    fn take_nodes(&mut self) -> Vec<TokenNode> {
+        mem::take(&mut self.lexemes)
+            .into_iter()
+            .map(|lex| TokenNode {
+                tok: lex.tok,
+                text: lex.text.into_owned(),
+                comment: lex.comment.into_owned(),
+            })
+            .collect()
+    }
+}
+
+impl<'a, L: Iterator<Item = Result<Lexeme<'a>, LexError>>> Iterator for Recorder<'a, L> {
+    type Item = Result<Lexeme<'a>, LexError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let lex = self.lex.next()?;
+        if let Ok(lex) = &lex {
+            self.lexemes.push(lex.clone());
+        }
+        Some(lex)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::token::{Encoding, LexOptions, Lexer, LineEnding, Mapping, ReadLexer};
+
+    #[test]
+    fn cst_round_trip() {
+        let srcs: [&[u8]; 5] = [
+            b"push 1: \t\r\n\tprint\r\n \t\r\nloop  \t\t\r\n\r\n\r\n\r\nend",
+            include_bytes!("../tests/leading_zero_labels.ws"),
+            include_bytes!("../tests/encoding_invalid_utf8_lazy.ws"),
+            include_bytes!("../tests/copy_error_unused.ws"),
+            include_bytes!("../tests/unknown_instruction_tltl.ws"),
+        ];
+        let opts = LexOptions {
+            line_ending: LineEnding::Crlf,
+            ..LexOptions::default()
+        };
+        for src in srcs {
+            let lex = Lexer::with_options(&src, Mapping::default(), opts);
+            let cst = Cst::parse(lex, ParseOptions::default()).unwrap();
+            let mut buf = Vec::new();
+            cst.write_to(&mut buf).unwrap();
+            assert_eq!(buf, src);
+            let lex = ReadLexer::new(src, Mapping::default(), opts);
+            assert_eq!(Cst::parse(lex, ParseOptions::default()), Ok(cst.clone()));
+            for node in &cst.insts {
+                if let Ok(inst) = &node.inst {
+                    let mut toks = Vec::new();
+                    inst.to_tokens(&mut toks);
+                    assert!(toks.iter().eq(node.toks.iter().map(|tok| &tok.tok)));
+                }
+            }
+        }
+
+        let src = b"push\t1 \t\r\n\rend\n\n\n";
+        let cst = Cst::parse(Lexer::new(src, Mapping::default()), ParseOptions::default()).unwrap();
+        let mut e = Emitter::new(&Mapping::new("S", "T", "L").unwrap(), Encoding::Utf8);
+        cst.emit(&mut e);
+        assert_eq!(e.finish(), b"pushT1ST\rL\rendLLL");
+    }
+}
@@ -0,0 +1,143 @@
+// Copyright (c) 2021 Andrew Archibald
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use crate::syntax::{Inst, Parser};
+use crate::token::{Encoding, LexOptions, Lexer, Mapping};
+use bstr::ByteSlice;
+use std::{cmp::Reverse, collections::HashMap};
+
+/// Number of the most frequent characters in the source that are tried
+/// as tokens.
+const MAX_CANDIDATES: usize = 10;
+
+/// How cleanly a source parses with a mapping. Scores compare
+/// lexicographically by field, from worst to best.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Score {
+    /// Parsed without unknown instructions or unterminated arguments
+    pub valid: bool,
+    /// The last instruction is `end`
+    pub ends: bool,
+    /// Number of tokens in instructions parsed before any error
+    pub toks: usize,
+}
+
+/// Ranks mappings of single characters from the source by how cleanly
+/// the source parses with each under the lexing options, best first. Ties
+/// are broken by character frequency.
+#[must_use]
+pub fn detect_mappings(src: &[u8], opts: LexOptions) -> Vec<(Mapping, Score)> {
+    let mut counts = HashMap::<char, usize>::new();
+    match opts.encoding {
+        Encoding::Utf8 => src
+            .chars()
+            .filter(|&ch| ch != char::REPLACEMENT_CHARACTER)
+            .for_each(|ch| *counts.entry(ch).or_default() += 1),
+        Encoding::Bytes => src
+            .iter()
+            .for_each(|&b| *counts.entry(b as char).or_default() += 1),
+    }
+    let mut chars = counts.into_iter().collect::<Vec<_>>();
+    chars.sort_by_key(|&(ch, count)| (Reverse(count), ch));
+    chars.truncate(MAX_CANDIDATES);
+
+    let mut ranked = Vec::new();
+    for &(s, _) in &chars {
+        for &(t, _) in &chars {
+            for &(l, _) in &chars {
+                if let Some(map) = Mapping::new(String::from(s), String::from(t), String::from(l)) {
+                    let score = score(src, &map, opts);
+                    ranked.push((map, score));
+                }
+            }
+        }
+    }
+    ranked.sort_by_key(|&(_, score)| Reverse(score));
+    ranked
+}
+
+fn score(src: &[u8], map: &Mapping, opts: LexOptions) -> Score {
+    let mut score = Score {
+        valid: true,
+        ends: false,
+        toks: 0,
+    };
+    let mut toks = Vec::new();
+    for inst in Parser::new(Lexer::with_options(&src, map.clone(), opts)) {
+        match inst {
+            Ok(inst) => {
+                toks.clear();
+                inst.inst.to_tokens(&mut toks);
+                score.toks += toks.len();
+                score.ends = inst.inst == Inst::End;
+            }
+            Err(_) => {
+                score.valid = false;
+                score.ends = false;
+                break;
+            }
+        }
+    }
+    score
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::emit::Emitter;
+    use crate::token::test::{TUTORIAL_SRC, TUTORIAL_TOKENS};
+    use crate::token::LineEnding;
+
+    #[test]
+    fn detect_tutorial() {
+        let insts = Parser::new(Lexer::new(&TUTORIAL_SRC, Mapping::default()))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let map = Mapping::new("x", "y", "z").unwrap();
+        let mut e = Emitter::new(&map, Encoding::Utf8);
+        e.push_comment(b"Count from 1 to 10: ");
+        insts.iter().for_each(|inst| e.push_inst(&inst.inst));
+        let src = e.finish();
+
+        let ranked = detect_mappings(&src, LexOptions::default());
+        let (best, score) = &ranked[0];
+        assert_eq!(best, &map);
+        assert_eq!(
+            *score,
+            Score {
+                valid: true,
+                ends: true,
+                toks: TUTORIAL_TOKENS.len(),
+            }
+        );
+        let opts = LexOptions {
+            encoding: Encoding::Bytes,
+            ..LexOptions::default()
+        };
+        assert_eq!(detect_mappings(TUTORIAL_SRC, opts)[0].0, Mapping::default());
+    }
+
+    #[test]
+    fn detect_line_endings() {
+        // Mix CR and LF line breaks, without forming CRLF
+        let mut src = TUTORIAL_SRC.to_vec();
+        for i in 1..src.len() {
+            if src[i - 1] == b'\n' && src[i] != b'\n' {
+                src[i - 1] = b'\r';
+            }
+        }
+
+        let opts = LexOptions {
+            line_ending: LineEnding::Cr,
+            ..LexOptions::default()
+        };
+        let (_, score) = &detect_mappings(&src, opts)[0];
+        assert!(score.valid && score.ends);
+        assert_eq!(score.toks, TUTORIAL_TOKENS.len());
+        let (_, score) = &detect_mappings(&src, LexOptions::default())[0];
+        assert!(!score.valid);
+    }
+}
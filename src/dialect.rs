@@ -0,0 +1,440 @@
+// Copyright (c) 2021 Andrew Archibald
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use crate::ext::Extensions;
+use crate::syntax::Inst;
+use clap::ArgEnum;
+use std::{borrow::Cow, collections::HashMap, error, fmt, ops::RangeInclusive, str::FromStr};
+
+/// Assembly syntax of a Whitespace assembler, used to format
+/// disassembly. Dialects are defined in a subset of TOML; see
+/// dialects/yspace.toml for the keys.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Dialect {
+    pub name: String,
+    /// Mnemonics that differ from the yspace mnemonic of each instruction
+    pub mnemonics: HashMap<String, String>,
+    pub casing: Casing,
+    /// Text between a mnemonic and its argument
+    pub arg_separator: String,
+    pub label_def: LabelDef,
+    /// Text before label names in definitions and references
+    pub label_prefix: String,
+    pub label_names: LabelNames,
+    /// Characters that may start a label identifier
+    pub ident_start: CharClass,
+    /// Characters that may follow the start of a label identifier
+    pub ident_chars: CharClass,
+    /// Prefix of labels written in binary to keep their leading zeros, or
+    /// empty, when the dialect has no binary labels and the zeros are lost
+    pub binary_prefix: String,
+    /// Whether constants may be written as char literals
+    pub chars: bool,
+    pub escapes: Escapes,
+    /// Whether strings may be written with pseudo-instructions
+    pub strings: bool,
+    /// Line comment marker
+    pub comment: String,
+}
+
+/// Case of mnemonics.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ArgEnum)]
+pub enum Casing {
+    /// As written in the mnemonic table
+    Keep,
+    Lower,
+    Upper,
+}
+
+/// Syntax for defining labels.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ArgEnum)]
+pub enum LabelDef {
+    /// Name followed by a colon, like `loop:`
+    Colon,
+    /// Mnemonic followed by the name, like `label loop`
+    Mnemonic,
+}
+
+/// Forms of label names, besides numbers.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ArgEnum)]
+pub enum LabelNames {
+    /// Identifiers and quoted strings
+    Quoted,
+    /// Identifiers only
+    Ident,
+    /// Numbers only
+    None,
+}
+
+/// Escapes in char and string literals.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ArgEnum)]
+pub enum Escapes {
+    /// Rust escapes, like `'\n'` and `'\u{7f}'`
+    Rust,
+    /// No escapes, so only printable ASCII characters other than the quote
+    /// and backslash can be written in literals
+    None,
+}
+
+/// Set of characters, written like a regular expression character class
+/// without brackets, such as `A-Za-z_`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CharClass(Vec<RangeInclusive<char>>);
+
+impl CharClass {
+    #[inline]
+    #[must_use]
+    pub fn contains(&self, ch: char) -> bool {
+        self.0.iter().any(|r| r.contains(&ch))
+    }
+}
+
+impl FromStr for CharClass {
+    type Err = ();
+
+    /// Parses ranges like `a-z` and single characters. A `-` at the start
+    /// or end is literal.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let chars = s.chars().collect::<Vec<_>>();
+        let mut ranges = Vec::new();
+        let mut i = 0;
+        while i < chars.len() {
+            match chars[i..] {
+                [lo, '-', hi, ..] if lo <= hi => {
+                    ranges.push(lo..=hi);
+                    i += 3;
+                }
+                [_, '-', _, ..] => return Err(()),
+                [ch, ..] => {
+                    ranges.push(ch..=ch);
+                    i += 1;
+                }
+                [] => unreachable!(),
+            }
+        }
+        Ok(CharClass(ranges))
+    }
+}
+
+/// Built-in dialect definitions by name.
+const BUILTIN: [(&str, &str); 5] = [
+    ("yspace", include_str!("../dialects/yspace.toml")),
+    ("whitelips", include_str!("../dialects/whitelips.toml")),
+    ("burghard", include_str!("../dialects/burghard.toml")),
+    (
+        "littlebughunter",
+        include_str!("../dialects/littlebughunter.toml"),
+    ),
+    ("rdebath", include_str!("../dialects/rdebath.toml")),
+];
+
+/// Keys outside of the mnemonics section.
+const KEYS: [&str; 13] = [
+    "name",
+    "casing",
+    "arg_separator",
+    "label_def",
+    "label_prefix",
+    "label_names",
+    "ident_start",
+    "ident_chars",
+    "binary_prefix",
+    "chars",
+    "escapes",
+    "strings",
+    "comment",
+];
+
+/// yspace mnemonics of the standard instructions.
+const MNEMONICS: [&str; 24] = [
+    "push", "dup", "copy", "swap", "drop", "slide", "add", "sub", "mul", "div", "mod", "store",
+    "retrieve", "label", "call", "jmp", "jz", "jn", "ret", "end", "printc", "printi", "readc",
+    "readi",
+];
+
+impl Dialect {
+    /// Gets a built-in dialect by name.
+    #[must_use]
+    pub fn builtin(name: &str) -> Option<Self> {
+        let (_, def) = BUILTIN.iter().find(|(builtin, _)| *builtin == name)?;
+        Some(def.parse().expect("invalid built-in dialect"))
+    }
+
+    /// Returns the mnemonic for an instruction or for a pseudo-instruction
+    /// named by its yspace mnemonic.
+    #[must_use]
+    pub fn mnemonic<'a>(&'a self, name: &'a str) -> Cow<'a, str> {
+        let m = self.mnemonics.get(name).map_or(name, String::as_str);
+        match self.casing {
+            Casing::Keep => Cow::Borrowed(m),
+            Casing::Lower => Cow::Owned(m.to_lowercase()),
+            Casing::Upper => Cow::Owned(m.to_uppercase()),
+        }
+    }
+
+    /// Reports whether a label name can be written without quotes.
+    #[must_use]
+    pub fn is_ident(&self, s: &str) -> bool {
+        let mut chars = s.chars();
+        matches!(chars.next(), Some(ch) if self.ident_start.contains(ch))
+            && chars.all(|ch| self.ident_chars.contains(ch))
+    }
+
+    /// Formats a char literal, or returns `None` when the character cannot
+    /// be written with the dialect's escapes.
+    #[must_use]
+    pub fn char_literal(&self, ch: char) -> Option<String> {
+        match self.escapes {
+            Escapes::Rust => Some(format!("{:?}", ch)),
+            Escapes::None => is_plain(ch, '\'').then(|| format!("'{}'", ch)),
+        }
+    }
+
+    /// Formats a string literal, or returns `None` when a character cannot
+    /// be written with the dialect's escapes.
+    #[must_use]
+    pub fn str_literal(&self, s: &str) -> Option<String> {
+        match self.escapes {
+            Escapes::Rust => Some(format!("{:?}", s)),
+            Escapes::None => s
+                .chars()
+                .all(|ch| is_plain(ch, '"'))
+                .then(|| format!("\"{}\"", s)),
+        }
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn inst_mnemonic<'a>(&'a self, inst: &Inst) -> Cow<'a, str> {
+        self.mnemonic(inst.wsa_opcode())
+    }
+}
+
+impl Default for Dialect {
+    fn default() -> Self {
+        Dialect {
+            name: "yspace".to_owned(),
+            mnemonics: HashMap::new(),
+            casing: Casing::Keep,
+            arg_separator: " ".to_owned(),
+            label_def: LabelDef::Colon,
+            label_prefix: String::new(),
+            label_names: LabelNames::Quoted,
+            ident_start: "A-Za-z_".parse().unwrap(),
+            ident_chars: "A-Za-z0-9_".parse().unwrap(),
+            binary_prefix: "0b".to_owned(),
+            chars: true,
+            escapes: Escapes::Rust,
+            strings: true,
+            comment: "#".to_owned(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DialectError {
+    pub kind: DialectErrorKind,
+    /// 1-based line of the error
+    pub line: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DialectErrorKind {
+    /// Line is not a section header or a key-value pair
+    Syntax,
+    UnknownSection(String),
+    UnknownKey(String),
+    /// Value has the wrong type or is not one of the options for the key
+    InvalidValue(String),
+    /// Mnemonic given for an instruction that does not exist
+    UnknownInst(String),
+}
+
+/// Value of a key in a dialect definition.
+enum Value {
+    Str(String),
+    Bool(bool),
+}
+
+impl FromStr for Dialect {
+    type Err = DialectError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let exts = Extensions::builtin();
+        let mut d = Dialect {
+            name: String::new(),
+            ..Dialect::default()
+        };
+        let mut in_mnemonics = false;
+        for (i, line) in s.lines().enumerate() {
+            let err = |kind| DialectError { kind, line: i + 1 };
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Some(section) = line.strip_prefix('[') {
+                let section = strip_comment(section)
+                    .strip_suffix(']')
+                    .ok_or_else(|| err(DialectErrorKind::Syntax))?;
+                if section != "mnemonics" {
+                    return Err(err(DialectErrorKind::UnknownSection(section.to_owned())));
+                }
+                in_mnemonics = true;
+                continue;
+            }
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| err(DialectErrorKind::Syntax))?;
+            let key = key.trim();
+            if in_mnemonics && !MNEMONICS.contains(&key) && exts.get(key).is_none() {
+                return Err(err(DialectErrorKind::UnknownInst(key.to_owned())));
+            } else if !in_mnemonics && !KEYS.contains(&key) {
+                return Err(err(DialectErrorKind::UnknownKey(key.to_owned())));
+            }
+            let value = parse_value(value.trim()).ok_or_else(|| err(DialectErrorKind::Syntax))?;
+            let invalid = || err(DialectErrorKind::InvalidValue(key.to_owned()));
+            if in_mnemonics {
+                match value {
+                    Value::Str(m) if !m.is_empty() => d.mnemonics.insert(key.to_owned(), m),
+                    _ => return Err(invalid()),
+                };
+                continue;
+            }
+            match (key, value) {
+                ("name", Value::Str(v)) => d.name = v,
+                ("casing", Value::Str(v)) => {
+                    d.casing = Casing::from_str(&v, false).map_err(|_| invalid())?
+                }
+                ("arg_separator", Value::Str(v)) if !v.is_empty() => d.arg_separator = v,
+                ("label_def", Value::Str(v)) => {
+                    d.label_def = LabelDef::from_str(&v, false).map_err(|_| invalid())?
+                }
+                ("label_prefix", Value::Str(v)) => d.label_prefix = v,
+                ("label_names", Value::Str(v)) => {
+                    d.label_names = LabelNames::from_str(&v, false).map_err(|_| invalid())?
+                }
+                ("ident_start", Value::Str(v)) => {
+                    d.ident_start = v.parse().map_err(|_| invalid())?
+                }
+                ("ident_chars", Value::Str(v)) => {
+                    d.ident_chars = v.parse().map_err(|_| invalid())?
+                }
+                ("binary_prefix", Value::Str(v)) => d.binary_prefix = v,
+                ("chars", Value::Bool(v)) => d.chars = v,
+                ("escapes", Value::Str(v)) => {
+                    d.escapes = Escapes::from_str(&v, false).map_err(|_| invalid())?
+                }
+                ("strings", Value::Bool(v)) => d.strings = v,
+                ("comment", Value::Str(v)) if !v.is_empty() => d.comment = v,
+                _ => return Err(invalid()),
+            }
+        }
+        Ok(d)
+    }
+}
+
+/// Reports whether a character can be written in a literal without
+/// escapes.
+#[inline]
+fn is_plain(ch: char, quote: char) -> bool {
+    (ch == ' ' || ch.is_ascii_graphic()) && ch != quote && ch != '\\'
+}
+
+/// Parses a double-quoted string with `\"` and `\\` escapes or a bool,
+/// followed by an optional comment.
+fn parse_value(s: &str) -> Option<Value> {
+    let rest = match s.strip_prefix('"') {
+        Some(rest) => rest,
+        None => {
+            return match strip_comment(s) {
+                "true" => Some(Value::Bool(true)),
+                "false" => Some(Value::Bool(false)),
+                _ => None,
+            }
+        }
+    };
+    let mut v = String::new();
+    let mut chars = rest.char_indices();
+    while let Some((i, ch)) = chars.next() {
+        match ch {
+            '"' => {
+                return match strip_comment(&rest[i + 1..]) {
+                    "" => Some(Value::Str(v)),
+                    _ => None,
+                };
+            }
+            '\\' => match chars.next()? {
+                (_, ch @ ('"' | '\\')) => v.push(ch),
+                _ => return None,
+            },
+            ch => v.push(ch),
+        }
+    }
+    None
+}
+
+/// Removes a trailing comment and surrounding whitespace.
+fn strip_comment(s: &str) -> &str {
+    s.split_once('#').map_or(s, |(s, _)| s).trim()
+}
+
+impl error::Error for DialectError {}
+
+impl fmt::Display for DialectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use DialectErrorKind::*;
+        match &self.kind {
+            Syntax => write!(f, "invalid dialect syntax")?,
+            UnknownSection(section) => write!(f, "unknown dialect section [{}]", section)?,
+            UnknownKey(key) => write!(f, "unknown dialect key {}", key)?,
+            InvalidValue(key) => write!(f, "invalid value for dialect key {}", key)?,
+            UnknownInst(name) => write!(f, "unknown instruction {} in dialect", name)?,
+        }
+        write!(f, " on line {}", self.line)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_dialects() {
+        for (name, _) in BUILTIN {
+            assert_eq!(Dialect::builtin(name).unwrap().name, name);
+        }
+        assert_eq!(Dialect::builtin("yspace"), Some(Dialect::default()));
+
+        let d = "name = \"test\" # comment\ncasing = \"upper\"\n\n[mnemonics]\njmp = \"jump\"\n"
+            .parse::<Dialect>()
+            .unwrap();
+        assert_eq!(d.mnemonic("jmp"), "JUMP");
+        assert_eq!(d.mnemonic("push"), "PUSH");
+
+        let d = "escapes = \"none\"".parse::<Dialect>().unwrap();
+        assert_eq!(d.char_literal('a'), Some("'a'".to_owned()));
+        assert_eq!(d.char_literal('\''), None);
+        assert_eq!(d.char_literal('\n'), None);
+        assert_eq!(d.str_literal("a b"), Some("\"a b\"".to_owned()));
+        assert_eq!(d.str_literal("é"), None);
+
+        let err = |s: &str| s.parse::<Dialect>().unwrap_err();
+        assert_eq!(
+            err("chars = \"yes\"").kind,
+            DialectErrorKind::InvalidValue("chars".to_owned())
+        );
+        assert_eq!(
+            err("casing = \"title\"").kind,
+            DialectErrorKind::InvalidValue("casing".to_owned())
+        );
+        assert_eq!(
+            err("color = true").kind,
+            DialectErrorKind::UnknownKey("color".to_owned())
+        );
+        assert_eq!(err("[mnemonics]\nnop = \"nop\"").line, 2);
+        assert_eq!(err("comment = \"#").kind, DialectErrorKind::Syntax);
+    }
+}
@@ -0,0 +1,616 @@
+// Copyright (c) 2021 Andrew Archibald
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use crate::dialect::{Dialect, LabelDef, LabelNames};
+use crate::syntax::{Inst, Int, Label, ParsedInst, Sign};
+use crate::token::{Encoding, Mapping, Token::*};
+use std::ops::Range;
+
+/// Formats instructions as Whitespace assembly in a dialect, with
+/// arguments rendered so that they reassemble to identical bits. Features
+/// are only used when the dialect supports them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Disassembler {
+    pub dialect: Dialect,
+    /// Render labels with printable UTF-8 bits as names
+    pub label_names: bool,
+    /// Render constants that are likely characters as char literals
+    pub chars: bool,
+    /// Collapse runs of characters into string literals, when `chars` is
+    /// set
+    pub strings: bool,
+}
+
+/// Line of assembly formatted from one or more consecutive instructions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmLine {
+    /// Indices of the instructions on the line
+    pub insts: Range<usize>,
+    pub text: String,
+}
+
+/// How the end of a string pushed to the stack is marked, following
+/// `--string-style` in docs/cli_draft.txt.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum StringStyle {
+    /// Terminated with 0, written `push.0`
+    Zero,
+    /// Terminated with -1, written `push.neg`
+    Neg,
+    /// Prefixed with its length, written `push.len`
+    Len,
+    /// No prefix or suffix, written `push`
+    Raw,
+}
+
+impl StringStyle {
+    /// Returns the suffix appended to the `push` mnemonic for strings in
+    /// this style.
+    #[inline]
+    #[must_use]
+    pub const fn suffix(&self) -> &'static str {
+        match self {
+            StringStyle::Zero => ".0",
+            StringStyle::Neg => ".neg",
+            StringStyle::Len => ".len",
+            StringStyle::Raw => "",
+        }
+    }
+}
+
+impl Default for Disassembler {
+    fn default() -> Self {
+        Disassembler {
+            dialect: Dialect::default(),
+            label_names: true,
+            chars: true,
+            strings: true,
+        }
+    }
+}
+
+impl Disassembler {
+    /// Formats a sequence of instructions, using the instructions around
+    /// each `push` to decide whether its constant is a character. Runs of
+    /// characters pushed together or printed one at a time are collapsed
+    /// into string literals.
+    #[must_use]
+    pub fn fmt_insts(&self, insts: &[&Inst]) -> Vec<AsmLine> {
+        let chars = self.chars && self.dialect.chars;
+        let strings = chars && self.strings && self.dialect.strings;
+        let char_args = if chars {
+            char_args(insts)
+        } else {
+            vec![false; insts.len()]
+        };
+        let mut lines = Vec::new();
+        let mut i = 0;
+        while i < insts.len() {
+            if strings {
+                let string = find_string(&insts[i..])
+                    .and_then(|(len, style, s)| Some((len, style, self.dialect.str_literal(&s)?)));
+                if let Some((len, style, s)) = string {
+                    let op = match style {
+                        Some(style) => {
+                            format!("{}{}", self.dialect.mnemonic("push"), style.suffix())
+                        }
+                        None => self.dialect.mnemonic("printc").into_owned(),
+                    };
+                    lines.push(AsmLine {
+                        insts: i..i + len,
+                        text: self.fmt_op(&op, &s),
+                    });
+                    i += len;
+                    continue;
+                }
+            }
+            let text = match insts[i] {
+                Inst::Push(n) if char_args[i] => {
+                    match as_char(n).and_then(|ch| self.dialect.char_literal(ch)) {
+                        Some(ch) => self.fmt_op(&self.dialect.inst_mnemonic(insts[i]), &ch),
+                        None => self.fmt_inst(insts[i]),
+                    }
+                }
+                inst => self.fmt_inst(inst),
+            };
+            lines.push(AsmLine {
+                insts: i..i + 1,
+                text,
+            });
+            i += 1;
+        }
+        lines
+    }
+
+    /// Formats an annotated listing with a row for each instruction, with
+    /// its byte offset, position, tokens, size encoded with the mapping,
+    /// assembly, and comment. Columns are aligned across the rows.
+    #[must_use]
+    pub fn fmt_listing(
+        &self,
+        insts: &[&ParsedInst],
+        map: &Mapping,
+        encoding: Encoding,
+    ) -> Vec<String> {
+        let d = Disassembler {
+            strings: false,
+            ..self.clone()
+        };
+        let asm = d.fmt_insts(&insts.iter().map(|inst| &inst.inst).collect::<Vec<_>>());
+        let mut toks = Vec::new();
+        let rows = insts
+            .iter()
+            .map(|inst| {
+                toks.clear();
+                inst.inst.to_tokens(&mut toks);
+                let size = toks
+                    .iter()
+                    .map(|&tok| map.encode(tok, encoding).len())
+                    .sum::<usize>();
+                [
+                    inst.span.start.offset.to_string(),
+                    inst.span.start.to_string(),
+                    toks.iter().map(|tok| tok.to_string()).collect(),
+                    size.to_string(),
+                ]
+            })
+            .collect::<Vec<_>>();
+        let width = |col: usize| rows.iter().map(|row| row[col].len()).max().unwrap_or(0);
+        let widths = [width(0), width(1), width(2), width(3)];
+        rows.iter()
+            .zip(insts)
+            .zip(asm)
+            .map(|((row, inst), line)| {
+                let mut s = format!(
+                    "{:>w0$} {:<w1$} {:<w2$} {:>w3$}  {}",
+                    row[0],
+                    row[1],
+                    row[2],
+                    row[3],
+                    line.text,
+                    w0 = widths[0],
+                    w1 = widths[1],
+                    w2 = widths[2],
+                    w3 = widths[3],
+                );
+                let comment = fmt_comment(&inst.comment().unwrap_or_default());
+                if !comment.is_empty() {
+                    s.push_str("  ");
+                    s.push_str(&self.dialect.comment);
+                    s.push(' ');
+                    s.push_str(&comment);
+                }
+                s
+            })
+            .collect()
+    }
+
+    /// Formats a single instruction without context, so constants are
+    /// always numbers.
+    #[must_use]
+    pub fn fmt_inst(&self, inst: &Inst) -> String {
+        let op = self.dialect.inst_mnemonic(inst);
+        match inst {
+            Inst::Label(l) if self.dialect.label_def == LabelDef::Colon => {
+                format!("{}:", self.fmt_label(l))
+            }
+            _ => {
+                if let Some(n) = inst.arg() {
+                    self.fmt_op(&op, &n.value().to_string())
+                } else if let Some(l) = inst.label() {
+                    self.fmt_op(&op, &self.fmt_label(l))
+                } else {
+                    op.into_owned()
+                }
+            }
+        }
+    }
+
+    #[inline]
+    fn fmt_op(&self, op: &str, arg: &str) -> String {
+        format!("{}{}{}", op, self.dialect.arg_separator, arg)
+    }
+
+    /// Formats a label as an identifier or a quoted string, when its bits
+    /// are printable UTF-8 and the dialect allows it, and otherwise as a
+    /// number. Labels with leading zeros are written in binary to keep
+    /// the zeros, when the dialect has binary labels.
+    #[must_use]
+    pub fn fmt_label(&self, l: &Label) -> String {
+        let prefix = &self.dialect.label_prefix;
+        let names = if self.label_names {
+            self.dialect.label_names
+        } else {
+            LabelNames::None
+        };
+        match l.as_utf8() {
+            Some(s)
+                if names != LabelNames::None && !s.is_empty() && !s.contains(char::is_control) =>
+            {
+                if self.dialect.is_ident(s) {
+                    return format!("{}{}", prefix, s);
+                } else if names == LabelNames::Quoted {
+                    if let Some(s) = self.dialect.str_literal(s) {
+                        return format!("{}{}", prefix, s);
+                    }
+                }
+            }
+            _ => {}
+        }
+        if l.has_leading_zeros() && !self.dialect.binary_prefix.is_empty() {
+            let mut toks = Vec::new();
+            l.to_tokens(&mut toks);
+            toks.pop();
+            let bits = toks.iter().map(|&tok| if tok == T { '1' } else { '0' });
+            let bin = &self.dialect.binary_prefix;
+            format!("{}{}{}", prefix, bin, bits.collect::<String>())
+        } else {
+            format!("{}{}", prefix, l)
+        }
+    }
+}
+
+/// Finds the `push` instructions with constants that are likely
+/// characters: those printed immediately by `printc` and those subtracted
+/// from a value read by `readc`, as in comparisons.
+fn char_args(insts: &[&Inst]) -> Vec<bool> {
+    let readc_addrs = (0..insts.len())
+        .filter(|&i| insts[i] == &Inst::Readc)
+        .filter_map(|i| pushed_before(insts, i))
+        .collect::<Vec<_>>();
+    // Reports whether the value on top of the stack before instruction
+    // `i` was retrieved from an address written by `readc`
+    let from_readc = |i: usize| {
+        let i = skip_dups(insts, i);
+        i > 0
+            && insts[i - 1] == &Inst::Retrieve
+            && matches!(pushed_before(insts, i - 1), Some(addr) if readc_addrs.contains(&addr))
+    };
+    insts
+        .iter()
+        .enumerate()
+        .map(|(i, inst)| match inst {
+            Inst::Push(n) if as_char(n).is_some() => match insts.get(i + 1) {
+                Some(Inst::Printc) => true,
+                Some(Inst::Sub) => from_readc(i),
+                _ => false,
+            },
+            _ => false,
+        })
+        .collect()
+}
+
+/// Returns the constant pushed before instruction `i`, skipping any
+/// `dup` instructions that copy it.
+fn pushed_before<'a>(insts: &[&'a Inst], i: usize) -> Option<&'a Int> {
+    match insts[..skip_dups(insts, i)].last() {
+        Some(Inst::Push(n)) => Some(n),
+        _ => None,
+    }
+}
+
+/// Moves back from instruction `i` past any preceding `dup` instructions.
+fn skip_dups(insts: &[&Inst], mut i: usize) -> usize {
+    while i > 0 && insts[i - 1] == &Inst::Dup {
+        i -= 1;
+    }
+    i
+}
+
+/// Converts a number to a char, when it is a Unicode scalar value with
+/// the minimal encoding, so that a char literal reassembles to identical
+/// bits.
+fn as_char(n: &Int) -> Option<char> {
+    if n.sign() != Sign::Pos || n.bit_len() == 0 || n.has_leading_zeros() {
+        return None;
+    }
+    char::from_u32(n.value().to_u32()?)
+}
+
+/// Minimum number of characters for a run to be formatted as a string.
+const MIN_STRING_LEN: usize = 2;
+
+/// Finds a string at the start of `insts`, returning the number of
+/// instructions it covers and its style. Characters printed by
+/// `push`-`printc` pairs have no style and are written as `printc "…"`.
+/// Runs of pushed characters are written in the order they are popped,
+/// with the pseudo-instruction for their `StringStyle`. Runs must be
+/// printed to be considered strings.
+fn find_string(insts: &[&Inst]) -> Option<(usize, Option<StringStyle>, String)> {
+    let printed = insts
+        .chunks_exact(2)
+        .map_while(|pair| match pair {
+            [Inst::Push(n), Inst::Printc] => as_char(n),
+            _ => None,
+        })
+        .collect::<String>();
+    if printed.chars().count() >= MIN_STRING_LEN {
+        let len = printed.chars().count() * 2;
+        return Some((len, None, printed));
+    }
+
+    let pushed = insts
+        .iter()
+        .map_while(|inst| match inst {
+            Inst::Push(n) => Some(n),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    let (style, len, chars) = match &pushed[..] {
+        [term, rest @ ..] if *term == &Int::new(0) => (StringStyle::Zero, 1, string_chars(rest)),
+        [term, rest @ ..] if *term == &Int::new(-1) => (StringStyle::Neg, 1, string_chars(rest)),
+        _ => {
+            // A length prefix may itself be a printable character
+            let mut chars = string_chars(&pushed);
+            if let Some(n) = (1..chars.len()).find(|&n| *pushed[n] == Int::new(n)) {
+                chars.truncate(n);
+            }
+            match pushed.get(chars.len()) {
+                Some(&n) if *n == Int::new(chars.len()) => (StringStyle::Len, 1, chars),
+                _ => (StringStyle::Raw, 0, chars),
+            }
+        }
+    };
+    let len = len + chars.len();
+    if chars.len() < MIN_STRING_LEN || !is_printed(&insts[len..]) {
+        return None;
+    }
+    Some((len, Some(style), chars.iter().rev().collect()))
+}
+
+/// Reports whether values pushed before `insts` are used by `call`, by
+/// `printc`, or by a loop starting there that prints with `printc`.
+fn is_printed(insts: &[&Inst]) -> bool {
+    match insts.first() {
+        Some(Inst::Call(_) | Inst::Printc) => true,
+        Some(Inst::Label(_)) => insts[1..]
+            .iter()
+            .take_while(|inst| {
+                !matches!(inst, Inst::Label(_) | Inst::Jmp(_) | Inst::Ret | Inst::End)
+            })
+            .any(|inst| inst == &&Inst::Printc),
+        _ => false,
+    }
+}
+
+/// Takes the leading numbers that are likely characters in a string:
+/// printable characters and common whitespace.
+fn string_chars(nums: &[&Int]) -> Vec<char> {
+    nums.iter()
+        .map_while(|n| as_char(n))
+        .take_while(|&ch| !ch.is_control() || matches!(ch, '\n' | '\t' | '\r'))
+        .collect()
+}
+
+/// Formats comment text on a single line.
+#[must_use]
+pub fn fmt_comment(comment: &[u8]) -> String {
+    let comment = String::from_utf8_lossy(comment);
+    comment.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::syntax::Parser;
+    use crate::token::{Lexer, Token};
+
+    /// Constructs a label with the UTF-8 bits of a string.
+    fn utf8(s: &str) -> Label {
+        let toks = s.bytes().flat_map(|b| {
+            (0..8)
+                .rev()
+                .map(move |i| if b >> i & 1 == 1 { T } else { S })
+        });
+        Label::from_tokens(toks.collect::<Vec<Token>>())
+    }
+
+    #[test]
+    fn disasm_labels() {
+        let d = Disassembler::default();
+        assert_eq!(d.fmt_inst(&Inst::Label(utf8("loop"))), "loop:");
+        assert_eq!(d.fmt_inst(&Inst::Jmp(utf8("size_2"))), "jmp size_2");
+        assert_eq!(d.fmt_inst(&Inst::Jmp(utf8("größe"))), r#"jmp "größe""#);
+        assert_eq!(d.fmt_label(&utf8("a \"b\"")), r#""a \"b\"""#);
+        assert_eq!(d.fmt_label(&utf8("\0")), "0b00000000");
+        assert_eq!(d.fmt_label(&Label::from_tokens(&[T, S])), "2");
+        assert_eq!(d.fmt_label(&Label::from_tokens(&[S, T])), "0b01");
+        assert_eq!(d.fmt_label(&Label::from_tokens(&[])), "0");
+
+        let d = Disassembler {
+            label_names: false,
+            ..Disassembler::default()
+        };
+        assert_eq!(d.fmt_label(&utf8("A")), "0b01000001");
+
+        let mut dialect = Dialect::builtin("rdebath").unwrap();
+        assert_eq!(dialect.binary_prefix, "");
+        dialect.label_names = LabelNames::Ident;
+        let d = Disassembler {
+            dialect,
+            ..Disassembler::default()
+        };
+        assert_eq!(d.fmt_label(&utf8("$x")), "$x");
+        assert_eq!(d.fmt_label(&utf8("größe")), "29118107511660389");
+        assert_eq!(d.fmt_label(&Label::from_tokens(&[S, T])), "1");
+    }
+
+    #[test]
+    fn disasm_chars() {
+        let insts = [
+            Inst::Push(Int::new(72)),
+            Inst::Printc,
+            Inst::Push(Int::new(10)),
+            Inst::Printc,
+            Inst::Push(Int::new(1)),
+            Inst::Dup,
+            Inst::Readc,
+            Inst::Push(Int::new(1)),
+            Inst::Retrieve,
+            Inst::Dup,
+            Inst::Push(Int::new(39)),
+            Inst::Sub,
+            Inst::Push(Int::new(72)),
+            Inst::Printi,
+            Inst::Push(Int::from_tokens(&[S, S, T, S, S, S, S, S])),
+            Inst::Printc,
+        ];
+        let insts = insts.iter().collect::<Vec<_>>();
+        let asm = Disassembler::default().fmt_insts(&insts);
+        let asm = asm.into_iter().map(|line| line.text).collect::<Vec<_>>();
+        assert_eq!(asm[0], r#"printc "H\n""#);
+        assert_eq!(asm[1], "push 1");
+        assert_eq!(asm[7], r"push '\''");
+        assert_eq!(asm[9], "push 72");
+        assert_eq!(asm[11], "push 32");
+
+        let d = Disassembler {
+            chars: false,
+            ..Disassembler::default()
+        };
+        assert_eq!(d.fmt_insts(&insts)[0].text, "push 72");
+    }
+
+    #[test]
+    fn disasm_listing() {
+        let map = Mapping::new("S", "T", "L").unwrap();
+        let src = "SSSTL hi TLST\nSSSTSSSSTL TLSS TLSS";
+        let insts = Parser::new(Lexer::new(&src, map.clone()))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let insts = insts.iter().collect::<Vec<_>>();
+        let listing = Disassembler::default().fmt_listing(&insts, &map, Encoding::Utf8);
+        assert_eq!(
+            listing,
+            &[
+                " 0 1:1  SSSTL       5  push 1",
+                " 9 1:10 TLST        4  printi  # hi",
+                "14 2:1  SSSTSSSSTL 10  push '!'",
+                "25 2:12 TLSS        4  printc",
+                "30 2:17 TLSS        4  printc",
+            ]
+        );
+    }
+
+    #[test]
+    fn disasm_dialects() {
+        let insts = [
+            Inst::Label(utf8("loop")),
+            Inst::Push(Int::new(72)),
+            Inst::Printc,
+            Inst::Copy(Int::new(1)),
+            Inst::Push(Int::new(39)),
+            Inst::Printc,
+            Inst::Jmp(utf8("loop")),
+        ];
+        let insts = insts.iter().collect::<Vec<_>>();
+        let fmt = |dialect| {
+            let d = Disassembler {
+                dialect: Dialect::builtin(dialect).unwrap(),
+                ..Disassembler::default()
+            };
+            let lines = d.fmt_insts(&insts);
+            lines.into_iter().map(|line| line.text).collect::<Vec<_>>()
+        };
+        assert_eq!(
+            fmt("burghard"),
+            &[
+                "label loop",
+                "push 72",
+                "outC",
+                "copy 1",
+                "push 39",
+                "outC",
+                "jump loop"
+            ]
+        );
+        assert_eq!(
+            fmt("rdebath"),
+            &[
+                "loop:",
+                "push 'H'",
+                "outc",
+                "pick 1",
+                "push 39",
+                "outc",
+                "jump loop"
+            ]
+        );
+        assert_eq!(
+            fmt("littlebughunter"),
+            &["loop:", "PUSH 'H'", "OUTC", "COPY 1", "PUSH 39", "OUTC", "JMP loop"]
+        );
+        assert_eq!(
+            fmt("yspace"),
+            &[
+                "loop:",
+                "push 'H'",
+                "printc",
+                "copy 1",
+                r"push '\''",
+                "printc",
+                "jmp loop"
+            ]
+        );
+    }
+
+    #[test]
+    fn disasm_strings() {
+        let push_str = |s: &str| {
+            let pushes = s.chars().rev().map(|ch| Inst::Push(Int::new(ch as u32)));
+            pushes.collect::<Vec<_>>()
+        };
+        let fmt = |insts: Vec<Inst>| {
+            let insts = insts.iter().collect::<Vec<_>>();
+            let lines = Disassembler::default().fmt_insts(&insts);
+            (lines[0].insts.clone(), lines[0].text.clone())
+        };
+        let mut insts = vec![Inst::Push(Int::new(0))];
+        insts.extend(push_str("Hi!\n"));
+        insts.push(Inst::Call(Label::new(1).unwrap()));
+        assert_eq!(fmt(insts), (0..5, r#"push.0 "Hi!\n""#.to_owned()));
+
+        // Printed by a loop
+        let mut insts = vec![Inst::Push(Int::new(-1))];
+        insts.extend(push_str("ok"));
+        let l = Label::new(1).unwrap();
+        insts.extend([
+            Inst::Label(l.clone()),
+            Inst::Dup,
+            Inst::Jn(Label::new(2).unwrap()),
+            Inst::Printc,
+            Inst::Jmp(l),
+        ]);
+        assert_eq!(fmt(insts), (0..3, r#"push.neg "ok""#.to_owned()));
+
+        let mut insts = push_str("abc");
+        insts.extend([Inst::Push(Int::new(3)), Inst::Call(Label::new(1).unwrap())]);
+        assert_eq!(fmt(insts), (0..4, r#"push.len "abc""#.to_owned()));
+
+        // The length 36 is '$'
+        let s = "abcdefghijklmnopqrstuvwxyz0123456789";
+        let mut insts = push_str(s);
+        insts.extend([Inst::Push(Int::new(36)), Inst::Call(Label::new(1).unwrap())]);
+        assert_eq!(fmt(insts), (0..37, format!("push.len {:?}", s)));
+
+        // Stored, not printed
+        let insts = vec![
+            Inst::Push(Int::new(0)),
+            Inst::Push(Int::new(100)),
+            Inst::Push(Int::new(200)),
+            Inst::Store,
+        ];
+        assert_eq!(fmt(insts), (0..1, "push 0".to_owned()));
+
+        let mut insts = push_str("ab");
+        insts.extend([Inst::Printc, Inst::Printc]);
+        assert_eq!(fmt(insts), (0..2, r#"push "ab""#.to_owned()));
+
+        let mut insts = push_str("ab");
+        insts.push(Inst::Add);
+        assert_eq!(fmt(insts), (0..1, "push 98".to_owned()));
+    }
+}
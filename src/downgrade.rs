@@ -0,0 +1,177 @@
+// Copyright (c) 2021 Andrew Archibald
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use crate::syntax::{Inst, Int, ParsedInst, Version};
+use crate::token::Span;
+use rug::Integer;
+use std::{error, fmt};
+
+/// Rewrites instructions that are newer than a target version into
+/// equivalent sequences of instructions from that version.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Downgrade {
+    target: Version,
+    /// First heap address that may be overwritten by rewritten
+    /// instructions
+    scratch: Option<Integer>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DowngradeError {
+    pub kind: DowngradeErrorKind,
+    pub inst: Inst,
+    pub span: Span,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DowngradeErrorKind {
+    /// Extension instruction outside of any standard version
+    NonStandard,
+    /// Negative `copy` or `slide` argument, which has no equivalent
+    NegativeArg,
+    /// `copy` or `slide` argument over `MAX_DEPTH`
+    TooDeep,
+    /// `copy` needs heap scratch space, but no address was given
+    NoScratch,
+}
+
+/// Largest `copy` or `slide` argument that is rewritten. Rewrites grow
+/// linearly with the argument.
+pub const MAX_DEPTH: u32 = 1 << 16;
+
+impl Downgrade {
+    #[inline]
+    #[must_use]
+    pub const fn new(target: Version, scratch: Option<Integer>) -> Self {
+        Downgrade { target, scratch }
+    }
+
+    /// Rewrites an instruction for the target version, or returns `None`,
+    /// when it is already supported.
+    ///
+    /// In Whitespace 0.2, `slide n` is `n` pairs of `swap` and `drop`.
+    /// `copy n` stores the top `n` values to scratch addresses, duplicates
+    /// the value then on top, and restores them around it.
+    pub fn rewrite(&self, inst: &ParsedInst) -> Result<Option<Vec<Inst>>, DowngradeError> {
+        let err = |kind| DowngradeError {
+            kind,
+            inst: inst.inst.clone(),
+            span: inst.span,
+        };
+        match inst.inst.version() {
+            Some(v) if v <= self.target => return Ok(None),
+            Some(_) => {}
+            None => return Err(err(DowngradeErrorKind::NonStandard)),
+        }
+        let n = match &inst.inst {
+            Inst::Copy(n) | Inst::Slide(n) => n,
+            _ => unreachable!("only copy and slide are newer than 0.2"),
+        };
+        if *n.value() < 0 {
+            return Err(err(DowngradeErrorKind::NegativeArg));
+        }
+        let depth = n
+            .value()
+            .to_u32()
+            .filter(|&d| d <= MAX_DEPTH)
+            .ok_or_else(|| err(DowngradeErrorKind::TooDeep))?;
+        let mut insts = Vec::new();
+        if let Inst::Slide(_) = inst.inst {
+            for _ in 0..depth {
+                insts.push(Inst::Swap);
+                insts.push(Inst::Drop);
+            }
+            return Ok(Some(insts));
+        }
+        if depth == 0 {
+            return Ok(Some(vec![Inst::Dup]));
+        }
+        let scratch = self
+            .scratch
+            .as_ref()
+            .ok_or_else(|| err(DowngradeErrorKind::NoScratch))?;
+        let addr = |i: u32| Inst::Push(Int::new(scratch.clone() + i));
+        for i in 0..depth {
+            insts.extend([addr(i), Inst::Swap, Inst::Store]);
+        }
+        insts.extend([Inst::Dup, addr(depth), Inst::Swap, Inst::Store]);
+        for i in (0..depth).rev() {
+            insts.extend([addr(i), Inst::Retrieve]);
+        }
+        insts.extend([addr(depth), Inst::Retrieve]);
+        Ok(Some(insts))
+    }
+}
+
+impl error::Error for DowngradeError {}
+
+impl fmt::Display for DowngradeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use DowngradeErrorKind::*;
+        write!(f, "cannot downgrade {} at {}: ", self.inst, self.span.start)?;
+        match self.kind {
+            NonStandard => write!(f, "extension instruction"),
+            NegativeArg => write!(f, "negative argument"),
+            TooDeep => write!(f, "argument over {}", MAX_DEPTH),
+            NoScratch => write!(f, "no heap scratch address given"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::syntax::Parser;
+    use crate::token::{Lexer, Mapping};
+
+    #[test]
+    fn downgrade_copy_slide() {
+        let map = Mapping::new("S", "T", "L").unwrap();
+        let rewrite = |src: &str, scratch: Option<u32>| {
+            let inst = Parser::new(Lexer::new(&src, map.clone()))
+                .next()
+                .unwrap()
+                .unwrap();
+            Downgrade::new(Version::WS0_2, scratch.map(Integer::from))
+                .rewrite(&inst)
+                .map(|insts| {
+                    insts.map(|insts| insts.iter().map(Inst::to_string).collect::<Vec<_>>())
+                })
+                .map_err(|err| err.kind)
+        };
+        // copy 2
+        assert_eq!(
+            rewrite("STSSTSL", Some(100)),
+            Ok(Some(
+                [
+                    "push 100", "swap", "store", "push 101", "swap", "store", "dup", "push 102",
+                    "swap", "store", "push 101", "retrieve", "push 100", "retrieve", "push 102",
+                    "retrieve",
+                ]
+                .map(str::to_owned)
+                .to_vec()
+            ))
+        );
+        // copy 0
+        assert_eq!(rewrite("STSSL", None), Ok(Some(vec!["dup".to_owned()])));
+        // slide 2
+        assert_eq!(
+            rewrite("STLSTSL", None),
+            Ok(Some(
+                ["swap", "drop", "swap", "drop"].map(str::to_owned).to_vec()
+            ))
+        );
+        // push 1
+        assert_eq!(rewrite("SSSTL", None), Ok(None));
+        // copy 1
+        assert_eq!(rewrite("STSSTL", None), Err(DowngradeErrorKind::NoScratch));
+        // copy -1
+        assert_eq!(
+            rewrite("STSTTL", Some(0)),
+            Err(DowngradeErrorKind::NegativeArg)
+        );
+    }
+}
@@ -0,0 +1,127 @@
+// Copyright (c) 2021 Andrew Archibald
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use crate::syntax::{Inst, ParsedInst};
+use crate::token::{Encoding, Mapping, Token};
+use std::mem;
+use Token::*;
+
+/// Writes tokens and instructions as Whitespace source with a mapping.
+/// With `Encoding::Bytes`, the mapping must be Latin-1.
+pub struct Emitter {
+    /// Encoded patterns for S, T, and L
+    pats: [Vec<u8>; 3],
+    buf: Vec<u8>,
+    toks: Vec<Token>,
+}
+
+impl Emitter {
+    #[must_use]
+    pub fn new(map: &Mapping, encoding: Encoding) -> Self {
+        Emitter {
+            pats: [S, T, L].map(|tok| map.encode(tok, encoding).into_owned()),
+            buf: Vec::new(),
+            toks: Vec::new(),
+        }
+    }
+
+    #[inline]
+    pub fn push_token(&mut self, tok: Token) {
+        self.buf.extend_from_slice(&self.pats[tok as usize]);
+    }
+
+    /// Writes comment text, omitting any patterns in it that would be
+    /// lexed as tokens under the mapping. Comment text that would form a
+    /// pattern together with an adjacent token is not omitted.
+    pub fn push_comment(&mut self, comment: &[u8]) {
+        let mut i = 0;
+        while i < comment.len() {
+            let rest = &comment[i..];
+            match self
+                .pats
+                .iter()
+                .filter(|pat| rest.starts_with(pat))
+                .map(|pat| pat.len())
+                .max()
+            {
+                Some(len) => i += len,
+                None => {
+                    self.buf.push(comment[i]);
+                    i += 1;
+                }
+            }
+        }
+    }
+
+    pub fn push_inst(&mut self, inst: &Inst) {
+        let mut toks = mem::take(&mut self.toks);
+        toks.clear();
+        inst.to_tokens(&mut toks);
+        toks.iter().for_each(|&tok| self.push_token(tok));
+        self.toks = toks;
+    }
+
+    /// Writes an instruction with the comments preceding each of its
+    /// tokens.
+    pub fn push_parsed(&mut self, inst: &ParsedInst) {
+        let comments = match &inst.comments {
+            Some(comments) => comments,
+            None => return self.push_inst(&inst.inst),
+        };
+        let mut toks = mem::take(&mut self.toks);
+        toks.clear();
+        inst.inst.to_tokens(&mut toks);
+        for (i, &tok) in toks.iter().enumerate() {
+            if let Some(comment) = comments.get(i) {
+                self.push_comment(comment);
+            }
+            self.push_token(tok);
+        }
+        self.toks = toks;
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn finish(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::syntax::Parser;
+    use crate::token::{test::TUTORIAL_SRC, Lexer};
+
+    #[test]
+    fn emit_mappings() {
+        let stl = Mapping::new("S", "T", "L").unwrap();
+        let parse = |src: &[u8], map| {
+            Parser::new(Lexer::new(&src, map))
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap()
+        };
+        let emit = |insts: &[ParsedInst], map: &Mapping| {
+            let mut e = Emitter::new(map, Encoding::Utf8);
+            insts.iter().for_each(|inst| e.push_parsed(inst));
+            e.finish()
+        };
+
+        let insts = parse(TUTORIAL_SRC, Mapping::default());
+        let src = emit(&insts, &stl);
+        assert_eq!(
+            emit(&parse(&src, stl.clone()), &Mapping::default()),
+            TUTORIAL_SRC
+        );
+
+        let insts = parse(b"a'S' b \t\t\n", Mapping::default());
+        assert_eq!(emit(&insts, &stl), b"a''SbSTTL");
+
+        let ook = "Ook.,Ook!,Ook?".parse::<Mapping>().unwrap();
+        let insts = parse(b"Ook.\t\tOok\t", Mapping::default());
+        assert_eq!(emit(&insts, &ook), b"Ook!Ook!OokOok!");
+    }
+}
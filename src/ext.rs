@@ -0,0 +1,228 @@
+// Copyright (c) 2021 Andrew Archibald
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use crate::token::Token::{self, *};
+use std::{error, fmt};
+
+/// Sequences that no standard instruction starts with. Extension opcodes
+/// must start with one of these, so they never shadow a standard
+/// instruction.
+pub const FREE_PREFIXES: [&[Token]; 9] = [
+    &[S, T, T],
+    &[T, S, T, L],
+    &[T, S, L],
+    &[T, T, L],
+    &[T, L, S, L],
+    &[T, L, T, L],
+    &[T, L, L],
+    &[L, L, S],
+    &[L, L, T],
+];
+
+/// Non-standard instruction without arguments, identified by its wsa
+/// mnemonic.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Extension {
+    pub name: &'static str,
+    pub opcode: &'static [Token],
+}
+
+impl Extension {
+    /// Prints the stack for debugging.
+    pub const DEBUG_PRINTSTACK: Extension = Extension {
+        name: "debug_printstack",
+        opcode: &[L, L, S, S, S],
+    };
+    /// Prints the heap for debugging.
+    pub const DEBUG_PRINTHEAP: Extension = Extension {
+        name: "debug_printheap",
+        opcode: &[L, L, S, S, T],
+    };
+    /// Dumps the program state, as in pywhitespace.
+    pub const TRACE: Extension = Extension {
+        name: "trace",
+        opcode: &[L, L, T],
+    };
+    /// Randomly permutes the stack.
+    pub const SHUFFLE: Extension = Extension {
+        name: "shuffle",
+        opcode: &[S, T, T, S],
+    };
+}
+
+/// Registry of extensions recognized by the parser. Opcodes are
+/// prefix-free, so each parses unambiguously.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Extensions {
+    exts: Vec<Extension>,
+}
+
+impl Extensions {
+    #[inline]
+    #[must_use]
+    pub const fn new() -> Self {
+        Extensions { exts: Vec::new() }
+    }
+
+    /// Registry of `debug_printstack`, `debug_printheap`, `trace`, and
+    /// `shuffle`.
+    #[must_use]
+    pub fn builtin() -> Self {
+        let mut exts = Extensions::new();
+        for ext in [
+            Extension::DEBUG_PRINTSTACK,
+            Extension::DEBUG_PRINTHEAP,
+            Extension::TRACE,
+            Extension::SHUFFLE,
+        ] {
+            exts.register(ext).unwrap();
+        }
+        exts
+    }
+
+    pub fn register(&mut self, ext: Extension) -> Result<(), ExtensionError> {
+        if !FREE_PREFIXES.iter().any(|p| ext.opcode.starts_with(p)) {
+            return Err(ExtensionError::StandardOpcode(ext.name));
+        }
+        for other in &self.exts {
+            if other.name == ext.name {
+                return Err(ExtensionError::DuplicateName(ext.name));
+            }
+            if other.opcode.starts_with(ext.opcode) || ext.opcode.starts_with(other.opcode) {
+                return Err(ExtensionError::ConflictingOpcode(ext.name, other.name));
+            }
+        }
+        self.exts.push(ext);
+        Ok(())
+    }
+
+    #[must_use]
+    pub fn get(&self, name: &str) -> Option<&Extension> {
+        self.exts.iter().find(|ext| ext.name == name)
+    }
+
+    /// Finds the extension with the opcode, or reports whether any opcode
+    /// starts with `toks`.
+    #[must_use]
+    pub fn lookup(&self, toks: &[Token]) -> Lookup {
+        let mut prefix = false;
+        for ext in &self.exts {
+            if ext.opcode == toks {
+                return Lookup::Match(*ext);
+            }
+            prefix |= ext.opcode.starts_with(toks);
+        }
+        if prefix {
+            Lookup::Prefix
+        } else {
+            Lookup::None
+        }
+    }
+}
+
+impl const Default for Extensions {
+    #[inline]
+    fn default() -> Self {
+        Extensions::new()
+    }
+}
+
+/// Result of looking up tokens in the registry.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Lookup {
+    Match(Extension),
+    /// Tokens are the prefix of some opcode
+    Prefix,
+    None,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ExtensionError {
+    /// Opcode could be a standard instruction
+    StandardOpcode(&'static str),
+    /// Opcode is a prefix of another, or has another as its prefix
+    ConflictingOpcode(&'static str, &'static str),
+    DuplicateName(&'static str),
+}
+
+impl error::Error for ExtensionError {}
+
+impl fmt::Display for ExtensionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use ExtensionError::*;
+        match self {
+            StandardOpcode(name) => {
+                write!(f, "opcode of {} does not start with a free prefix", name)
+            }
+            ConflictingOpcode(name, other) => {
+                write!(f, "opcode of {} conflicts with opcode of {}", name, other)
+            }
+            DuplicateName(name) => write!(f, "extension {} is already registered", name),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::syntax::{Inst, ParseErrorKind, ParseOptions, Parser};
+    use crate::token::{Lexer, Mapping};
+
+    #[test]
+    fn register_extensions() {
+        let mut exts = Extensions::builtin();
+        let ext = |name, opcode| Extension { name, opcode };
+        assert_eq!(
+            exts.register(ext("nop", &[S, S, S])),
+            Err(ExtensionError::StandardOpcode("nop"))
+        );
+        assert_eq!(
+            exts.register(ext("nop", &[L, L, S])),
+            Err(ExtensionError::ConflictingOpcode("nop", "debug_printstack"))
+        );
+        assert_eq!(
+            exts.register(ext("eval", &[L, L, T])),
+            Err(ExtensionError::ConflictingOpcode("eval", "trace"))
+        );
+        assert_eq!(
+            exts.register(ext("trace", &[T, T, L])),
+            Err(ExtensionError::DuplicateName("trace"))
+        );
+        assert_eq!(exts.register(ext("nop", &[T, T, L])), Ok(()));
+        assert_eq!(exts.get("nop"), Some(&ext("nop", &[T, T, L])));
+    }
+
+    #[test]
+    fn parse_extensions() {
+        let map = Mapping::new("S", "T", "L").unwrap();
+        let src = b"LLSSS STTS LLT LLL LLSL";
+        let opts = ParseOptions {
+            extensions: Extensions::builtin(),
+            ..ParseOptions::default()
+        };
+        let insts = Parser::with_options(Lexer::new(src, map.clone()), opts)
+            .map(|inst| inst.map(|inst| inst.inst).map_err(|err| err.kind))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            insts,
+            &[
+                Ok(Inst::Ext(Extension::DEBUG_PRINTSTACK)),
+                Ok(Inst::Ext(Extension::SHUFFLE)),
+                Ok(Inst::Ext(Extension::TRACE)),
+                Ok(Inst::End),
+                Err(ParseErrorKind::UnknownOpcode),
+            ]
+        );
+        assert_eq!(insts[0].as_ref().unwrap().version(), None);
+        assert_eq!(insts[0].as_ref().unwrap().to_string(), "debug_printstack");
+
+        let mut p = Parser::new(Lexer::new(src, map));
+        assert_eq!(
+            p.next().unwrap().unwrap_err().kind,
+            ParseErrorKind::UnknownOpcode
+        );
+    }
+}
@@ -7,13 +7,43 @@
 #![feature(const_option, const_option_ext, const_trait_impl, inline_const)]
 
 mod bit_pack;
+mod cst;
+mod detect;
+mod dialect;
+mod disasm;
+mod downgrade;
+mod emit;
+mod ext;
+mod program;
+mod recover;
+mod spec;
 mod syntax;
 mod token;
 
+use bit_pack::{BitLexer, BitWriter};
 use clap::{ArgEnum, Parser as ClapParser};
-use std::{fs, path::PathBuf};
-use syntax::{Parser, Version};
-use token::{Lexer, Mapping};
+use cst::Cst;
+use detect::detect_mappings;
+use dialect::Dialect;
+use disasm::{fmt_comment, Disassembler};
+use downgrade::Downgrade;
+use emit::Emitter;
+use ext::Extensions;
+use program::{reachable, DupeLabels, InstSource, LazyParser, Program};
+use recover::RecoveringParser;
+use rug::Integer;
+use spec::SpecReport;
+use std::{
+    fs::{self, File},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    process,
+};
+use syntax::{Inst, LabelZeros, ParseOptions, ParsedInst, Parser, Version, ZeroNumber};
+use token::{
+    Encoding, LexError, LexOptions, Lexeme, Lexer, LineEnding, Mapping, ReadLexer, Remainder,
+    Utf8Check,
+};
 
 #[derive(ClapParser)]
 #[clap(version, about, long_about = None)]
@@ -21,38 +51,381 @@ struct Cli {
     /// Subcommand to execute
     #[clap(arg_enum)]
     command: Command,
-    /// Filename of Whitespace program
+    /// Filename of Whitespace program, or `-` for stdin
     file: PathBuf,
-    /// Token mapping
+    /// Program format, guessed from the file extension by default, or wsx
+    /// when unpacking
+    #[clap(short, long, arg_enum)]
+    format: Option<Format>,
+    /// Token mapping, as three characters or three comma-separated
+    /// patterns, like `Ook.,Ook!,Ook?`
     #[clap(short, long, default_value_t)]
     mapping: Mapping,
+    /// Source encoding
+    #[clap(long, arg_enum, default_value = "utf8")]
+    encoding: Encoding,
+    /// When to report invalid UTF-8
+    #[clap(long, arg_enum, default_value = "lazy")]
+    utf8_check: Utf8Check,
+    /// Line breaks recognized as LF
+    #[clap(long, arg_enum, default_value = "lf")]
+    line_ending: LineEnding,
+    /// Token mapping to convert to
+    #[clap(long, default_value_t)]
+    to: Mapping,
+    /// Forms accepted for a number argument of zero
+    #[clap(long, arg_enum, default_value = "empty")]
+    zero_number: ZeroNumber,
+    /// Whether labels differing only by leading zeros are distinct
+    #[clap(long, arg_enum, default_value = "distinct")]
+    label_zeros: LabelZeros,
+    /// Recognize the debug_printstack, debug_printheap, trace, and
+    /// shuffle extension instructions
+    #[clap(long)]
+    extensions: bool,
+    /// Check only the instructions reachable from the start, parsing
+    /// lazily like wspace. No syntax errors are reported after the
+    /// last-reachable instruction. The first occurrence of a label is used
+    /// for branch destinations.
+    #[clap(long, conflicts_with = "dupe-labels")]
+    lazy_parse: bool,
+    /// Definition used for branches to duplicate labels
+    #[clap(long, arg_enum, default_value = "first")]
+    dupe_labels: DupeLabels,
+    /// Show comments next to disassembled instructions or keep them when
+    /// converting
+    #[clap(long)]
+    comments: bool,
+    /// Convert without losing any source text, including comments, line
+    /// breaks, and tokens after a syntax error
+    #[clap(long, conflicts_with_all = &["spec", "comments"])]
+    lossless: bool,
+    /// Continue disassembling after syntax errors
+    #[clap(long)]
+    recover: bool,
+    /// Disassemble labels as numbers, even when their bits are UTF-8
+    #[clap(long)]
+    numeric_labels: bool,
+    /// Disassemble constants as numbers, even when they are likely
+    /// characters or strings
+    #[clap(long)]
+    numeric_chars: bool,
+    /// Disassemble as a listing with the offset, position, tokens, size,
+    /// and comment of each instruction
+    #[clap(long)]
+    listing: bool,
+    /// Assembly dialect to disassemble to, as a built-in name (yspace,
+    /// whitelips, burghard, littlebughunter, or rdebath) or the path of a
+    /// definition file
+    #[clap(long, default_value = "yspace")]
+    dialect: String,
+    /// Display the spec report as JSON
+    #[clap(long)]
+    json: bool,
+    /// Specification version to downgrade to when converting
+    #[clap(long, arg_enum)]
+    spec: Option<Version>,
+    /// First heap address that a downgrade may overwrite as scratch space
+    #[clap(long)]
+    scratch: Option<Integer>,
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, ArgEnum)]
 enum Command {
     /// Disassemble program
     Disasm,
-    /// Display Whitespace specification required by program
+    /// Display the Whitespace specification and features used by
+    /// program and which implementations would accept it
     Spec,
+    /// Pack program into the bit-packed wsx format
+    Pack,
+    /// Unpack program into Whitespace source with the mapping
+    Unpack,
+    /// Convert program to another mapping, optionally downgrading it to
+    /// an older specification
+    Convert,
+    /// Detect the mapping of program and display it
+    DetectMapping,
+    /// Check that program parses and its labels resolve, and display the
+    /// target of each branch
+    Check,
 }
 
-fn main() -> std::io::Result<()> {
+#[derive(Copy, Clone, PartialEq, Eq, ArgEnum)]
+enum Format {
+    /// Whitespace source
+    Ws,
+    /// Bit-packed Whitespace
+    Wsx,
+}
+
+fn main() -> io::Result<()> {
     let cli = Cli::parse();
-    let src = fs::read(cli.file)?;
-    let mut p = Parser::new(Lexer::new(&src, cli.mapping));
+    let format = cli.format.unwrap_or_else(|| match cli.file.extension() {
+        _ if cli.command == Command::Unpack => Format::Wsx,
+        Some(ext) if ext == "wsx" => Format::Wsx,
+        _ => Format::Ws,
+    });
+    if cli.encoding == Encoding::Bytes && !(cli.mapping.is_latin1() && cli.to.is_latin1()) {
+        eprintln!("error: mapping has characters outside of a byte");
+        process::exit(1);
+    }
+    let opts = LexOptions {
+        encoding: cli.encoding,
+        utf8_check: cli.utf8_check,
+        line_ending: cli.line_ending,
+    };
+    let mut r: Box<dyn Read> = if cli.file == Path::new("-") {
+        Box::new(io::stdin())
+    } else {
+        Box::new(File::open(&cli.file)?)
+    };
+    if cli.command == Command::DetectMapping {
+        let mut src = Vec::new();
+        r.read_to_end(&mut src)?;
+        match detect_mappings(&src, opts).first() {
+            Some((map, _)) => println!("{:#}", map),
+            None => {
+                eprintln!("error: too few distinct characters to detect mapping");
+                process::exit(1);
+            }
+        }
+        return Ok(());
+    }
+    // Stream the source, unless it needs to be read up front
+    let eager = cli.encoding == Encoding::Utf8 && cli.utf8_check == Utf8Check::Eager;
+    if format == Format::Ws && !eager {
+        return run(&cli, format, ReadLexer::new(r, cli.mapping.clone(), opts));
+    }
+    let mut src = Vec::new();
+    r.read_to_end(&mut src)?;
+    match format {
+        Format::Ws => run(
+            &cli,
+            format,
+            Lexer::with_options(&src, cli.mapping.clone(), opts),
+        ),
+        Format::Wsx => run(&cli, format, BitLexer::new(&src).lexemes()),
+    }
+}
+
+fn run<'a, L>(cli: &Cli, format: Format, lex: L) -> io::Result<()>
+where
+    L: Iterator<Item = Result<Lexeme<'a>, LexError>> + Remainder,
+{
+    let opts = ParseOptions {
+        zero_number: cli.zero_number,
+        label_zeros: cli.label_zeros,
+        extensions: if cli.extensions {
+            Extensions::builtin()
+        } else {
+            Extensions::new()
+        },
+    };
     match cli.command {
-        Command::Disasm => p.for_each(|inst| println!("{}", inst)),
+        Command::Disasm => {
+            let dialect = match Dialect::builtin(&cli.dialect) {
+                Some(dialect) => dialect,
+                None => match fs::read_to_string(&cli.dialect) {
+                    Ok(def) => unwrap_or_exit(def.parse()),
+                    Err(err) => {
+                        eprintln!("error: cannot read dialect {}: {}", cli.dialect, err);
+                        process::exit(1);
+                    }
+                },
+            };
+            let d = Disassembler {
+                dialect,
+                label_names: !cli.numeric_labels,
+                chars: !cli.numeric_chars,
+                ..Disassembler::default()
+            };
+            let failed = if cli.recover {
+                let insts = RecoveringParser::with_options(lex, opts);
+                print_disasm(
+                    cli,
+                    &d,
+                    insts.map(|inst| inst.map_err(|err| err.to_string())),
+                )
+            } else {
+                let insts = Parser::with_options(lex, opts);
+                print_disasm(
+                    cli,
+                    &d,
+                    insts.map(|inst| inst.map_err(|err| err.to_string())),
+                )
+            };
+            if failed {
+                process::exit(1);
+            }
+        }
         Command::Spec => {
-            if p.any(|inst| inst.version() == Version::WS0_3) {
-                println!("0.3");
+            // Parse permissively, so that dialect features are reported
+            // rather than rejected
+            let opts = ParseOptions {
+                extensions: opts.extensions,
+                ..ParseOptions::default()
+            };
+            let report = SpecReport::new(Parser::with_options(lex, opts));
+            if cli.json {
+                print!("{}", report.to_json());
+            } else {
+                print!("{}", report);
+            }
+        }
+        Command::Pack => {
+            let mut w = BitWriter::new();
+            w.extend(lex.map(|lex| unwrap_or_exit(lex).tok));
+            io::stdout().write_all(&w.finish())?;
+        }
+        Command::Unpack => {
+            let mut e = Emitter::new(&cli.mapping, cli.encoding);
+            lex.for_each(|lex| e.push_token(unwrap_or_exit(lex).tok));
+            io::stdout().write_all(&e.finish())?;
+        }
+        Command::Convert if cli.lossless => {
+            let cst = unwrap_or_exit(Cst::parse(lex, opts));
+            // Tokens keep their exact text, unless the mapping changes or
+            // they were bit-packed
+            if format == Format::Ws && cli.to == cli.mapping {
+                cst.write_to(&mut io::stdout())?;
             } else {
-                println!("0.2");
+                let mut e = Emitter::new(&cli.to, cli.encoding);
+                cst.emit(&mut e);
+                io::stdout().write_all(&e.finish())?;
+            }
+        }
+        Command::Convert => {
+            let mut e = Emitter::new(&cli.to, cli.encoding);
+            let downgrade = cli.spec.map(|v| Downgrade::new(v, cli.scratch.clone()));
+            for inst in Parser::with_options(lex, opts) {
+                let inst = unwrap_or_exit(inst);
+                let rewritten = match &downgrade {
+                    Some(d) => unwrap_or_exit(d.rewrite(&inst)),
+                    None => None,
+                };
+                match rewritten {
+                    Some(insts) => {
+                        // Comments are kept before the rewritten sequence
+                        if let Some(c) = inst.comment().filter(|_| cli.comments) {
+                            e.push_comment(&c);
+                        }
+                        insts.iter().for_each(|inst| e.push_inst(inst));
+                    }
+                    None if cli.comments => e.push_parsed(&inst),
+                    None => e.push_inst(&inst.inst),
+                }
             }
+            io::stdout().write_all(&e.finish())?;
         }
+        Command::Check if cli.lazy_parse => {
+            let mut p = LazyParser::with_options(lex, opts);
+            for pc in unwrap_or_exit(reachable(&mut p)) {
+                if let Some(target) = p.target(pc) {
+                    let target = unwrap_or_exit(target);
+                    let inst = unwrap_or_exit(p.inst(pc).unwrap()).clone();
+                    let target = unwrap_or_exit(p.inst(target).unwrap()).span.start;
+                    println!("{}: {} -> {}", inst.span.start, inst.inst, target);
+                }
+            }
+        }
+        Command::Check => {
+            let prog = unwrap_or_exit(Program::parse(
+                Parser::with_options(lex, opts),
+                cli.dupe_labels,
+            ));
+            for (i, inst) in prog.insts().iter().enumerate() {
+                let (target, l) = match (prog.target(i), inst.inst.label()) {
+                    (Some(target), Some(l)) => (target, l),
+                    _ => continue,
+                };
+                let defs = prog.definitions(l);
+                print!(
+                    "{}: {} -> {}",
+                    inst.span.start,
+                    inst.inst,
+                    prog.insts()[target].span.start
+                );
+                if defs.len() > 1 {
+                    let n = defs.iter().position(|&d| d == target).unwrap() + 1;
+                    print!(" (definition {} of {})", n, defs.len());
+                }
+                println!();
+            }
+        }
+        Command::DetectMapping => unreachable!("handled before lexing"),
     }
     Ok(())
 }
 
+/// Most instructions formatted together when disassembling.
+const DISASM_WINDOW: usize = 4096;
+
+/// Prints the disassembly of instructions and the errors between them,
+/// returning whether there were any errors.
+fn print_disasm<I>(cli: &Cli, d: &Disassembler, insts: I) -> bool
+where
+    I: Iterator<Item = Result<ParsedInst, String>>,
+{
+    // Whether a constant is a character depends on its neighbors, so
+    // instructions are formatted in windows, which end where control
+    // cannot fall through. Listings align their columns across all rows,
+    // so are only split by errors.
+    let mut window = Vec::new();
+    let mut failed = false;
+    for inst in insts {
+        match inst {
+            Ok(inst) => {
+                let ends = matches!(inst.inst, Inst::Jmp(_) | Inst::Ret | Inst::End);
+                window.push(inst);
+                if !cli.listing && (ends || window.len() >= DISASM_WINDOW) {
+                    print_insts(cli, d, &window);
+                    window.clear();
+                }
+            }
+            Err(err) => {
+                print_insts(cli, d, &window);
+                window.clear();
+                eprintln!("error: {}", err);
+                failed = true;
+            }
+        }
+    }
+    print_insts(cli, d, &window);
+    failed
+}
+
+fn print_insts(cli: &Cli, d: &Disassembler, insts: &[ParsedInst]) {
+    let insts = insts.iter().collect::<Vec<_>>();
+    if cli.listing {
+        for row in d.fmt_listing(&insts, &cli.mapping, cli.encoding) {
+            println!("{}", row);
+        }
+        return;
+    }
+    let lines = d.fmt_insts(&insts.iter().map(|inst| &inst.inst).collect::<Vec<_>>());
+    for line in lines {
+        let comment = insts[line.insts]
+            .iter()
+            .filter_map(|inst| inst.comment())
+            .collect::<Vec<_>>()
+            .concat();
+        match fmt_comment(&comment) {
+            c if cli.comments && !c.is_empty() => {
+                println!("{} {} {}", line.text, d.dialect.comment, c)
+            }
+            _ => println!("{}", line.text),
+        }
+    }
+}
+
+fn unwrap_or_exit<T, E: std::error::Error>(res: Result<T, E>) -> T {
+    res.unwrap_or_else(|err| {
+        eprintln!("error: {}", err);
+        process::exit(1);
+    })
+}
+
 #[test]
 fn verify_app() {
     use clap::IntoApp;
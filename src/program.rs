@@ -0,0 +1,427 @@
+// Copyright (c) 2021 Andrew Archibald
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use crate::syntax::{Inst, Label, ParseError, ParseOptions, ParsedInst, Parser};
+use crate::token::{LexError, Lexeme, Span};
+use clap::ArgEnum;
+use std::{collections::HashMap, error, fmt};
+
+/// Parsed program with the target of each branch resolved to the index
+/// of a `label` instruction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Program {
+    insts: Vec<ParsedInst>,
+    /// Resolved target of each instruction, when it is a branch
+    targets: Vec<Option<usize>>,
+    /// Indices of every definition of each label, in order
+    labels: HashMap<Label, Vec<usize>>,
+}
+
+/// Instructions addressed by index for execution, with branch targets
+/// resolved. `Program` parses eagerly and `LazyParser` parses as
+/// execution requires.
+pub trait InstSource {
+    /// Gets the instruction at `pc`, or `None` past the end of the
+    /// program.
+    fn inst(&mut self, pc: usize) -> Option<Result<&ParsedInst, ProgramError>>;
+
+    /// Resolves the target of the branch at `pc`, or `None`, when it is
+    /// not a parsed branch.
+    fn target(&mut self, pc: usize) -> Option<Result<usize, ProgramError>>;
+}
+
+/// Finds the instructions reachable from the start of the program,
+/// following both outcomes of conditional branches and assuming that
+/// each call returns. With `LazyParser`, only the reachable part of the
+/// program is parsed. Returns their indices in order.
+pub fn reachable<S: InstSource>(src: &mut S) -> Result<Vec<usize>, ProgramError> {
+    let mut seen = Vec::new();
+    let mut work = vec![0];
+    while let Some(pc) = work.pop() {
+        if seen.get(pc) == Some(&true) {
+            continue;
+        }
+        let inst = match src.inst(pc) {
+            Some(inst) => inst?.inst.clone(),
+            // Falls off the end of the program
+            None => continue,
+        };
+        if seen.len() <= pc {
+            seen.resize(pc + 1, false);
+        }
+        seen[pc] = true;
+        if let Some(target) = src.target(pc) {
+            work.push(target?);
+        }
+        match inst {
+            Inst::Jmp(_) | Inst::Ret | Inst::End => {}
+            _ => work.push(pc + 1),
+        }
+    }
+    Ok((0..seen.len()).filter(|&pc| seen[pc]).collect())
+}
+
+/// Definition used as the target for branches to a duplicated label.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ArgEnum)]
+pub enum DupeLabels {
+    /// Reject duplicate labels (Nebula)
+    Error,
+    /// Use the first definition (wspace)
+    First,
+    /// Use the last definition (BlueSpace)
+    Last,
+    /// Use the next definition after the branch, wrapping around
+    Next,
+    /// Use the previous definition before the branch, wrapping around
+    Previous,
+}
+
+impl const Default for DupeLabels {
+    #[inline]
+    fn default() -> Self {
+        DupeLabels::First
+    }
+}
+
+impl Program {
+    pub fn new(insts: Vec<ParsedInst>, dupe_labels: DupeLabels) -> Result<Self, LabelError> {
+        let mut labels = HashMap::<Label, Vec<usize>>::new();
+        for (i, inst) in insts.iter().enumerate() {
+            if let Inst::Label(l) = &inst.inst {
+                let defs = labels.entry(l.clone()).or_default();
+                if dupe_labels == DupeLabels::Error && !defs.is_empty() {
+                    return Err(LabelError::new(LabelErrorKind::Duplicate, l, inst.span));
+                }
+                defs.push(i);
+            }
+        }
+
+        let mut targets = Vec::with_capacity(insts.len());
+        for (i, inst) in insts.iter().enumerate() {
+            let l = match &inst.inst {
+                Inst::Label(_) => None,
+                inst => inst.label(),
+            };
+            let target = match l {
+                Some(l) => {
+                    let defs = labels
+                        .get(l)
+                        .ok_or_else(|| LabelError::new(LabelErrorKind::Undefined, l, inst.span))?;
+                    let (first, last) = (defs[0], defs[defs.len() - 1]);
+                    Some(match dupe_labels {
+                        DupeLabels::Error | DupeLabels::First => first,
+                        DupeLabels::Last => last,
+                        DupeLabels::Next => defs.iter().copied().find(|&d| d > i).unwrap_or(first),
+                        DupeLabels::Previous => {
+                            defs.iter().rev().copied().find(|&d| d < i).unwrap_or(last)
+                        }
+                    })
+                }
+                None => None,
+            };
+            targets.push(target);
+        }
+
+        Ok(Program {
+            insts,
+            targets,
+            labels,
+        })
+    }
+
+    /// Parses the entire program at once and resolves its labels.
+    pub fn parse<I>(insts: I, dupe_labels: DupeLabels) -> Result<Self, ProgramError>
+    where
+        I: IntoIterator<Item = Result<ParsedInst, ParseError>>,
+    {
+        let insts = insts.into_iter().collect::<Result<Vec<_>, _>>()?;
+        Ok(Program::new(insts, dupe_labels)?)
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn insts(&self) -> &[ParsedInst] {
+        &self.insts
+    }
+
+    /// Index of the `label` instruction targeted by the branch at `i`.
+    #[inline]
+    #[must_use]
+    pub fn target(&self, i: usize) -> Option<usize> {
+        self.targets[i]
+    }
+
+    /// Indices of every definition of the label, in order.
+    #[inline]
+    #[must_use]
+    pub fn definitions(&self, l: &Label) -> &[usize] {
+        self.labels.get(l).map_or(&[], Vec::as_slice)
+    }
+}
+
+impl InstSource for Program {
+    #[inline]
+    fn inst(&mut self, pc: usize) -> Option<Result<&ParsedInst, ProgramError>> {
+        self.insts.get(pc).map(Ok)
+    }
+
+    #[inline]
+    fn target(&mut self, pc: usize) -> Option<Result<usize, ProgramError>> {
+        self.targets.get(pc).copied().flatten().map(Ok)
+    }
+}
+
+/// Parser that parses instructions only as far as execution requires and
+/// branches to the first definition of a label, like the Haskell
+/// reference interpreter. Syntax errors after the last-executed
+/// instruction are never reported.
+pub struct LazyParser<L> {
+    parser: Parser<L>,
+    insts: Vec<ParsedInst>,
+    /// Index of the first definition of each label parsed so far
+    labels: HashMap<Label, usize>,
+    err: Option<ParseError>,
+}
+
+impl<L> LazyParser<L> {
+    #[inline]
+    #[must_use]
+    pub fn with_options(lex: L, opts: ParseOptions) -> Self {
+        LazyParser {
+            parser: Parser::with_options(lex, opts),
+            insts: Vec::new(),
+            labels: HashMap::new(),
+            err: None,
+        }
+    }
+}
+
+impl<'a, L: Iterator<Item = Result<Lexeme<'a>, LexError>>> LazyParser<L> {
+    /// Parses the next instruction and reports whether there was one.
+    fn parse_next(&mut self) -> bool {
+        match self.parser.next() {
+            Some(Ok(inst)) => {
+                if let Inst::Label(l) = &inst.inst {
+                    self.labels.entry(l.clone()).or_insert(self.insts.len());
+                }
+                self.insts.push(inst);
+                true
+            }
+            Some(Err(err)) => {
+                self.err = Some(err);
+                false
+            }
+            None => false,
+        }
+    }
+}
+
+impl<'a, L: Iterator<Item = Result<Lexeme<'a>, LexError>>> InstSource for LazyParser<L> {
+    fn inst(&mut self, pc: usize) -> Option<Result<&ParsedInst, ProgramError>> {
+        while self.insts.len() <= pc && self.parse_next() {}
+        match self.insts.get(pc) {
+            Some(inst) => Some(Ok(inst)),
+            None => self.err.clone().map(|err| Err(err.into())),
+        }
+    }
+
+    fn target(&mut self, pc: usize) -> Option<Result<usize, ProgramError>> {
+        let l = match self.insts.get(pc).map(|inst| &inst.inst) {
+            Some(Inst::Label(_)) | None => return None,
+            Some(inst) => inst.label()?.clone(),
+        };
+        // Seek forward until the label is defined
+        loop {
+            if let Some(&target) = self.labels.get(&l) {
+                return Some(Ok(target));
+            }
+            if !self.parse_next() {
+                break;
+            }
+        }
+        Some(Err(match &self.err {
+            Some(err) => err.clone().into(),
+            None => LabelError::new(LabelErrorKind::Undefined, &l, self.insts[pc].span).into(),
+        }))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProgramError {
+    Parse(ParseError),
+    Label(LabelError),
+}
+
+impl From<ParseError> for ProgramError {
+    #[inline]
+    fn from(err: ParseError) -> Self {
+        ProgramError::Parse(err)
+    }
+}
+
+impl From<LabelError> for ProgramError {
+    #[inline]
+    fn from(err: LabelError) -> Self {
+        ProgramError::Label(err)
+    }
+}
+
+impl error::Error for ProgramError {}
+
+impl fmt::Display for ProgramError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProgramError::Parse(err) => write!(f, "{}", err),
+            ProgramError::Label(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LabelError {
+    pub kind: LabelErrorKind,
+    pub label: Label,
+    /// Span of the branch or duplicate definition
+    pub span: Span,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LabelErrorKind {
+    /// Branch to a label that is not defined
+    Undefined,
+    /// Label defined more than once, with `DupeLabels::Error`
+    Duplicate,
+}
+
+impl LabelError {
+    #[inline]
+    #[must_use]
+    fn new(kind: LabelErrorKind, label: &Label, span: Span) -> Self {
+        LabelError {
+            kind,
+            label: label.clone(),
+            span,
+        }
+    }
+}
+
+impl error::Error for LabelError {}
+
+impl fmt::Display for LabelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            LabelErrorKind::Undefined => write!(f, "undefined label ")?,
+            LabelErrorKind::Duplicate => write!(f, "duplicate label ")?,
+        }
+        write!(f, "{} at {}", self.label, self.span.start)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::syntax::Parser;
+    use crate::token::{Lexer, Mapping};
+
+    fn parse(src: &[u8], dupe_labels: DupeLabels) -> Result<Program, LabelError> {
+        let map = Mapping::new("S", "T", "L").unwrap();
+        let insts = Parser::new(Lexer::new(&src, map))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        Program::new(insts, dupe_labels)
+    }
+
+    #[test]
+    fn resolve_labels() {
+        // label 0; jmp 0; label 0; jmp 0; label 1
+        let src = b"LSSSL LSLSL LSSSL LSLSL LSSTL";
+        let targets = |dupe_labels| {
+            let prog = parse(src, dupe_labels).unwrap();
+            (0..prog.insts().len())
+                .map(|i| prog.target(i))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            targets(DupeLabels::First),
+            &[None, Some(0), None, Some(0), None]
+        );
+        assert_eq!(
+            targets(DupeLabels::Last),
+            &[None, Some(2), None, Some(2), None]
+        );
+        assert_eq!(
+            targets(DupeLabels::Next),
+            &[None, Some(2), None, Some(0), None]
+        );
+        assert_eq!(
+            targets(DupeLabels::Previous),
+            &[None, Some(0), None, Some(2), None]
+        );
+
+        let err = parse(src, DupeLabels::Error).unwrap_err();
+        assert_eq!(err.kind, LabelErrorKind::Duplicate);
+        assert_eq!(err.span.start.col, 13);
+        assert_eq!(err.to_string(), "duplicate label 0 at 1:13");
+    }
+
+    #[test]
+    fn undefined_labels() {
+        for name in ["call", "jmp", "jz", "jn"] {
+            let path = format!(
+                "{}/tests/undefined_label_{}.ws",
+                env!("CARGO_MANIFEST_DIR"),
+                name
+            );
+            let src = std::fs::read(path).unwrap();
+            let insts = Parser::new(Lexer::new(&src, Mapping::default()))
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap();
+            let err = Program::new(insts, DupeLabels::First).unwrap_err();
+            assert_eq!(err.kind, LabelErrorKind::Undefined);
+        }
+    }
+
+    #[test]
+    fn lazy_parse() {
+        let map = Mapping::new("S", "T", "L").unwrap();
+        // jmp 0; push 1; label 0; label 0; end; <unknown STT>
+        let src = b"LSLSL SSSTL LSSSL LSSSL LLL STT";
+        let mut p =
+            LazyParser::with_options(Lexer::new(&src, map.clone()), ParseOptions::default());
+        assert_eq!(p.target(0), None);
+        assert!(matches!(p.inst(0), Some(Ok(_))));
+        assert_eq!(p.target(0), Some(Ok(2)));
+        assert_eq!(p.insts.len(), 3);
+        assert_eq!(p.inst(4).unwrap().unwrap().inst, Inst::End);
+        assert!(matches!(p.inst(5), Some(Err(ProgramError::Parse(_)))));
+        let mut p =
+            LazyParser::with_options(Lexer::new(&src, map.clone()), ParseOptions::default());
+        assert_eq!(reachable(&mut p), Ok(vec![0, 2, 3, 4]));
+
+        let insts = Parser::new(Lexer::new(&src, map.clone()));
+        let err = Program::parse(insts, DupeLabels::First).unwrap_err();
+        assert!(matches!(err, ProgramError::Parse(_)));
+
+        // jmp 1; end
+        let mut p =
+            LazyParser::with_options(Lexer::new(b"LSLTL LLL", map), ParseOptions::default());
+        assert!(matches!(p.inst(0), Some(Ok(_))));
+        assert!(matches!(
+            p.target(0),
+            Some(Err(ProgramError::Label(LabelError {
+                kind: LabelErrorKind::Undefined,
+                ..
+            })))
+        ));
+
+        // copy 1; end. The copy only fails when its value is used.
+        let src = include_bytes!("../tests/copy_error_unused.ws");
+        let mut p =
+            LazyParser::with_options(Lexer::new(src, Mapping::default()), ParseOptions::default());
+        assert_eq!(reachable(&mut p), Ok(vec![0, 1]));
+        assert_eq!(p.inst(1).unwrap().unwrap().inst, Inst::End);
+    }
+}
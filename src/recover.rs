@@ -0,0 +1,400 @@
+// Copyright (c) 2021 Andrew Archibald
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use crate::ext::FREE_PREFIXES;
+use crate::syntax::{Inst, Label, ParseError, ParseOptions, ParsedInst, Parser};
+use crate::token::{
+    LexError, Lexeme, Pos, Span,
+    Token::{self, *},
+};
+use std::{collections::BTreeSet, collections::HashSet, error, fmt, str};
+
+/// Parser that recovers from syntax errors by skipping to where
+/// instructions most likely resynchronize, using the heuristics in
+/// docs/syntax_recovery.md. The whole source is lexed up front.
+pub struct RecoveringParser<'a> {
+    lexemes: Vec<Lexeme<'a>>,
+    toks: Vec<Token>,
+    lex_err: Option<LexError>,
+    opts: ParseOptions,
+    /// Index of the next token to parse
+    i: usize,
+    /// Labels defined so far
+    labels: HashSet<Label>,
+    /// Branch targets used so far without a definition
+    targets: HashSet<Label>,
+    done: bool,
+}
+
+/// Syntax error with the range of source invalidated to recover from it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecoveredError {
+    pub err: ParseError,
+    /// Source skipped from the start of the erroneous instruction to where
+    /// parsing resumed
+    pub invalid: Span,
+}
+
+/// Number of instructions that must parse from a position for parsing to
+/// be considered resynchronized there, unless EOF is reached first.
+const MIN_RESYNC_INSTS: usize = 3;
+
+impl<'a> RecoveringParser<'a> {
+    #[inline]
+    pub fn new<L: Iterator<Item = Result<Lexeme<'a>, LexError>>>(lex: L) -> Self {
+        RecoveringParser::with_options(lex, ParseOptions::default())
+    }
+
+    pub fn with_options<L>(lex: L, opts: ParseOptions) -> Self
+    where
+        L: Iterator<Item = Result<Lexeme<'a>, LexError>>,
+    {
+        let mut lexemes = Vec::new();
+        let mut lex_err = None;
+        for lex in lex {
+            match lex {
+                Ok(lex) => lexemes.push(lex),
+                Err(err) => {
+                    lex_err = Some(err);
+                    break;
+                }
+            }
+        }
+        let toks = lexemes.iter().map(|lex| lex.tok).collect();
+        RecoveringParser {
+            lexemes,
+            toks,
+            lex_err,
+            opts,
+            i: 0,
+            labels: HashSet::new(),
+            targets: HashSet::new(),
+            done: false,
+        }
+    }
+
+    /// Creates a parser starting at token `i`.
+    fn parser_at(
+        &self,
+        i: usize,
+    ) -> Parser<impl Iterator<Item = Result<Lexeme<'a>, LexError>> + '_> {
+        let lexemes = self.lexemes[i..].iter().cloned().map(Ok);
+        Parser::with_options(lexemes.chain(self.lex_err.map(Err)), self.opts.clone())
+    }
+
+    /// Finds the earliest token index after `start` at which parsing
+    /// resumes, so that the least source is invalidated. When parsing
+    /// from there would run through a position implied by a known label,
+    /// the two disagree on where instructions start, and the label is
+    /// used instead.
+    fn resync(&self, start: usize) -> usize {
+        let mut labeled = BTreeSet::new();
+        self.long_line_starts(start, &mut labeled);
+        self.label_starts(start, &mut labeled);
+        let mut candidates = labeled.clone();
+        self.impossible_prefix_starts(start, &mut candidates);
+        // Otherwise, skip to the next line
+        for i in start + 1..self.toks.len() {
+            if self.toks[i - 1] == L {
+                candidates.insert(i);
+            }
+        }
+        let resumes = |&i: &usize| {
+            let toks = &self.toks[i..];
+            !FREE_PREFIXES.iter().any(|p| toks.starts_with(p)) && self.parses_at(i)
+        };
+        let first = match candidates.range(start + 1..).copied().find(resumes) {
+            Some(first) => first,
+            None => return self.toks.len(),
+        };
+        let ends = self.inst_ends(first);
+        let end = ends.last().copied().unwrap_or(first);
+        labeled
+            .range(first + 1..end)
+            .copied()
+            .filter(|i| !ends.contains(i))
+            .find(resumes)
+            .unwrap_or(first)
+    }
+
+    /// Token index after `i` of the first token starting at or after `end`.
+    /// Bit-packed tokens share byte offsets, so the whole position is
+    /// compared.
+    fn index_at(&self, i: usize, end: Pos) -> usize {
+        i + self.lexemes[i..].partition_point(|lex| lex.span.start < end)
+    }
+
+    /// Reports whether enough instructions parse without error from `i`.
+    fn parses_at(&self, i: usize) -> bool {
+        let mut p = self.parser_at(i);
+        (0..MIN_RESYNC_INSTS).all(|_| !matches!(p.next(), Some(Err(_))))
+    }
+
+    /// Token indices after each of the instructions checked by
+    /// `parses_at` from `i`.
+    fn inst_ends(&self, i: usize) -> Vec<usize> {
+        self.parser_at(i)
+            .take(MIN_RESYNC_INSTS)
+            .map_while(Result::ok)
+            .map(|inst| self.index_at(i, inst.span.end))
+            .collect()
+    }
+
+    /// Finds lines after `start` that are long enough to be a label
+    /// argument, decodes them in reverse as UTF-8 or as a small integer,
+    /// and adds the starts of the `label`, `call`, `jmp`, `jz`, or `jn`
+    /// instructions they complete, when the label has been defined or
+    /// branched to. Otherwise, ordinary lines of ASCII bits would match.
+    fn long_line_starts(&self, start: usize, candidates: &mut BTreeSet<usize>) {
+        let toks = &self.toks;
+        let mut line_start = start;
+        while line_start > 0 && toks[line_start - 1] != L {
+            line_start -= 1;
+        }
+        for line in toks[line_start..].split_inclusive(|&tok| tok == L) {
+            let i = line_start;
+            line_start += line.len();
+            let line = match line.split_last() {
+                Some((L, line)) if line.len() >= 8 => line,
+                _ => continue,
+            };
+            let preceded_by = |prefix: &[Token]| toks[..i].ends_with(prefix);
+            let known = |arg: &[Token]| {
+                let l = Label::from_tokens(arg);
+                self.labels.contains(&l) || self.targets.contains(&l)
+            };
+            let rem = line.len() % 8;
+            if is_utf8_reversed(line) {
+                if rem == 2 && preceded_by(&[L]) && known(&line[2..]) {
+                    candidates.insert(i - 1);
+                } else if rem == 0 && preceded_by(&[L, S, L]) && known(line) {
+                    candidates.insert(i - 3);
+                }
+            } else {
+                match line {
+                    [S, S | T, T, ..] | [T, S | T, T, ..]
+                        if line.len() <= 10 && preceded_by(&[L]) && known(&line[2..]) =>
+                    {
+                        candidates.insert(i - 1);
+                    }
+                    [T, ..] if line.len() <= 8 && preceded_by(&[L, S, L]) && known(line) => {
+                        candidates.insert(i - 3);
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    /// Adds positions after `start` of branches to labels that have been
+    /// defined and of definitions of labels that have been branched to,
+    /// but not yet defined.
+    fn label_starts(&self, start: usize, candidates: &mut BTreeSet<usize>) {
+        let toks = &self.toks;
+        for i in start + 1..toks.len() {
+            let (branch, arg) = match &toks[i..] {
+                [L, S, S, arg @ ..] => (false, arg),
+                [L, S | T, S | T, arg @ ..] | [L, S, L, arg @ ..] => (true, arg),
+                _ => continue,
+            };
+            let len = match arg.iter().position(|&tok| tok == L) {
+                Some(len) => len,
+                None => continue,
+            };
+            let l = Label::from_tokens(&arg[..len]);
+            if branch && self.labels.contains(&l) || !branch && self.targets.contains(&l) {
+                candidates.insert(i);
+            }
+        }
+    }
+
+    /// Adds the positions after the first L in occurrences of impossible
+    /// prefixes after `start`. L cannot occur within a number or label,
+    /// so it most likely terminates an argument.
+    fn impossible_prefix_starts(&self, start: usize, candidates: &mut BTreeSet<usize>) {
+        let toks = &self.toks;
+        for i in start + 1..toks.len() {
+            for prefix in FREE_PREFIXES {
+                if toks[i..].starts_with(prefix) {
+                    if let Some(j) = prefix.iter().position(|&tok| tok == L) {
+                        candidates.insert(i + j + 1);
+                    }
+                }
+            }
+        }
+    }
+
+    fn record_labels(&mut self, inst: &Inst) {
+        match inst {
+            Inst::Label(l) => {
+                self.targets.remove(l);
+                self.labels.insert(l.clone());
+            }
+            _ => {
+                if let Some(l) = inst.label() {
+                    if !self.labels.contains(l) {
+                        self.targets.insert(l.clone());
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Reports whether the bits, read from the right in groups of 8, form
+/// valid UTF-8. Leading bits that do not fill a group are ignored.
+fn is_utf8_reversed(bits: &[Token]) -> bool {
+    let mut bytes = bits
+        .rchunks_exact(8)
+        .map(|byte| byte.iter().fold(0u8, |b, &tok| b << 1 | (tok == T) as u8))
+        .collect::<Vec<_>>();
+    bytes.reverse();
+    str::from_utf8(&bytes).is_ok()
+}
+
+impl<'a> Iterator for RecoveringParser<'a> {
+    type Item = Result<ParsedInst, RecoveredError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let start = self.i;
+        let res = self.parser_at(start).next();
+        match res {
+            Some(Ok(inst)) => {
+                self.i = self.index_at(start, inst.span.end);
+                self.record_labels(&inst.inst);
+                Some(Ok(inst))
+            }
+            Some(Err(err)) => {
+                let resume = self.resync(start);
+                let end = match self.lexemes.get(resume) {
+                    Some(lex) => lex.span.start,
+                    None => match self.lex_err {
+                        Some(err) => err.pos,
+                        None => self.lexemes.last().map_or(Pos::new(), |lex| lex.span.end),
+                    },
+                };
+                self.i = resume;
+                if resume == self.toks.len() {
+                    self.done = true;
+                }
+                Some(Err(RecoveredError {
+                    invalid: Span::new(err.span.start, end),
+                    err,
+                }))
+            }
+            None => {
+                self.done = true;
+                None
+            }
+        }
+    }
+}
+
+impl error::Error for RecoveredError {}
+
+impl fmt::Display for RecoveredError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}, invalidating {}", self.err, self.invalid)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bit_pack::{BitLexer, BitWriter};
+    use crate::syntax::ParseErrorKind;
+    use crate::token::{Lexer, Mapping};
+
+    type Parsed = Vec<Result<String, (ParseErrorKind, usize, usize)>>;
+
+    fn collect<'a, L: Iterator<Item = Result<Lexeme<'a>, LexError>>>(lex: L) -> Parsed {
+        RecoveringParser::new(lex)
+            .map(|res| match res {
+                Ok(inst) => Ok(inst.inst.to_string()),
+                Err(err) => Err((err.err.kind, err.invalid.start.col, err.invalid.end.col)),
+            })
+            .collect()
+    }
+
+    fn parse(src: &str) -> Parsed {
+        let map = Mapping::new("S", "T", "L").unwrap();
+        collect(Lexer::new(&src, map))
+    }
+
+    /// Parses the source packed as bits, with the columns of errors in bits.
+    fn parse_bits(src: &str) -> Parsed {
+        let map = Mapping::new("S", "T", "L").unwrap();
+        let mut w = BitWriter::new();
+        w.extend(Lexer::new(&src, map).map(|lex| lex.unwrap().tok));
+        let src = w.finish();
+        collect(BitLexer::new(&src).lexemes())
+    }
+
+    #[test]
+    fn recover_next_line() {
+        // push 1; <unknown STT>; push 2; push 3; add; end
+        let insts = parse("SSSTL STTSL SSSTSL SSSTTL TSSS LLL");
+        assert_eq!(
+            insts,
+            &[
+                Ok("push 1".to_owned()),
+                Err((ParseErrorKind::UnknownOpcode, 7, 13)),
+                Ok("push 2".to_owned()),
+                Ok("push 3".to_owned()),
+                Ok("add".to_owned()),
+                Ok("end".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn recover_labels() {
+        // label 97 ("a" as UTF-8); <unknown TSTL>; jmp 97; label 1; end
+        let insts = parse("LSSSTTSSSSTL TSTLT LSLSTTSSSSTL LSSTL LLL");
+        assert_eq!(insts[0], Ok("97:".to_owned()));
+        assert_eq!(insts[1], Err((ParseErrorKind::UnknownOpcode, 14, 20)));
+        assert_eq!(insts[2], Ok("jmp 97".to_owned()));
+
+        // label 1; <unknown TSTL>; push 2; push 3; add; push 65; jmp 1; end
+        let insts = parse("LSSTL TSTL SSSTSL SSSTTL TSSS SSSTSSSSSTL LSLTL LLL");
+        assert_eq!(insts[1], Err((ParseErrorKind::UnknownOpcode, 7, 12)));
+        assert_eq!(insts[2], Ok("push 2".to_owned()));
+        assert_eq!(insts.len(), 8);
+
+        // jmp 2; <unknown LLT>; label 2; end
+        let insts = parse("LSLTSL LLTT LSSTSL LLL");
+        assert_eq!(insts[1], Err((ParseErrorKind::UnknownOpcode, 8, 13)));
+        assert_eq!(insts[2], Ok("2:".to_owned()));
+        assert_eq!(insts.len(), 4);
+    }
+
+    #[test]
+    fn recover_bit_packed() {
+        // dup; <unknown STT>; push 2; push 3; add; end
+        let insts = parse_bits("SLS STTSL SSSTSL SSSTTL TSSS LLL");
+        assert_eq!(
+            insts,
+            &[
+                Ok("dup".to_owned()),
+                Err((ParseErrorKind::UnknownOpcode, 5, 13)),
+                Ok("push 2".to_owned()),
+                Ok("push 3".to_owned()),
+                Ok("add".to_owned()),
+                Ok("end".to_owned()),
+            ]
+        );
+
+        // label 1; <unknown TSTL>; push 2; jmp 1; end, where jmp 1 is a
+        // labeled candidate that ends push 2
+        let insts = parse_bits("LSSTL TSTL SSSTSL LSLTL LLL");
+        assert_eq!(insts[1], Err((ParseErrorKind::UnknownOpcode, 9, 16)));
+        assert_eq!(insts[2], Ok("push 2".to_owned()));
+        assert_eq!(insts.len(), 5);
+    }
+}
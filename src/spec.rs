@@ -0,0 +1,461 @@
+// Copyright (c) 2021 Andrew Archibald
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use crate::program::DupeLabels;
+use crate::syntax::{Inst, Label, LabelZeros, ParseError, ParsedInst, Sign, Version, ZeroNumber};
+use crate::token::Pos;
+use std::collections::HashSet;
+use std::fmt::{self, Write};
+
+/// Features used by a program that affect its portability between
+/// implementations. Each feature records the positions of the
+/// instructions using it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpecReport {
+    /// Lowest specification version with every standard instruction used
+    pub version: Version,
+    /// Names of extension instructions used, in order of first use
+    pub extensions: Vec<&'static str>,
+    /// Number arguments without a sign
+    pub empty_numbers: Vec<Pos>,
+    /// Number arguments with a sign, but no bits
+    pub sign_only_numbers: Vec<Pos>,
+    /// Label arguments without bits
+    pub empty_labels: Vec<Pos>,
+    /// Label arguments with leading zeros
+    pub leading_zero_labels: Vec<Pos>,
+    /// Label arguments that are not UTF-8 when read as bytes
+    pub non_utf8_labels: Vec<Pos>,
+    /// Label definitions that duplicate an earlier definition
+    pub dupe_labels: Vec<Pos>,
+    /// Label definitions that duplicate an earlier definition only when
+    /// leading zeros are ignored
+    pub zero_dupe_labels: Vec<Pos>,
+    /// `copy` and `slide` instructions with a negative argument
+    pub negative_copy_slide: Vec<Pos>,
+    /// Most significant bits in any number argument
+    pub max_number_bits: usize,
+    /// Whether execution can continue past the last instruction
+    pub implicit_end: bool,
+    /// Syntax error after which the rest of the source does not parse
+    pub trailing_junk: Option<ParseError>,
+}
+
+/// Parsing behavior of a known implementation, as listed in
+/// docs/differences.md. Behaviors not listed there are assumed to be the
+/// most permissive, except for duplicate labels and syntax errors, which
+/// are unknown.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Implementation {
+    pub name: &'static str,
+    pub zero_number: ZeroNumber,
+    /// Whether a label may have no bits
+    pub empty_labels: bool,
+    pub label_zeros: LabelZeros,
+    /// Handling of duplicate labels, when known
+    pub dupe_labels: Option<DupeLabels>,
+    /// Whether syntax errors are only reported once executed, when known
+    pub lazy_syntax: Option<bool>,
+    /// Significant bits representable in a number, when bounded
+    pub number_bits: Option<usize>,
+}
+
+pub const IMPLEMENTATIONS: &[Implementation] = &[
+    Implementation {
+        name: "wspace",
+        zero_number: ZeroNumber::Sign,
+        empty_labels: true,
+        label_zeros: LabelZeros::Distinct,
+        dupe_labels: Some(DupeLabels::First),
+        lazy_syntax: Some(true),
+        number_bits: None,
+    },
+    Implementation {
+        name: "Nebula",
+        zero_number: ZeroNumber::Empty,
+        empty_labels: true,
+        label_zeros: LabelZeros::Ignored,
+        dupe_labels: Some(DupeLabels::Error),
+        lazy_syntax: Some(false),
+        number_bits: Some(63),
+    },
+    Implementation {
+        name: "BlueSpace",
+        zero_number: ZeroNumber::Empty,
+        empty_labels: true,
+        label_zeros: LabelZeros::Distinct,
+        dupe_labels: Some(DupeLabels::Last),
+        lazy_syntax: Some(false),
+        number_bits: None,
+    },
+    Implementation {
+        name: "conrad",
+        zero_number: ZeroNumber::SignAndBits,
+        empty_labels: false,
+        label_zeros: LabelZeros::Distinct,
+        dupe_labels: None,
+        lazy_syntax: None,
+        number_bits: None,
+    },
+    Implementation {
+        name: "wsjq",
+        zero_number: ZeroNumber::Empty,
+        empty_labels: true,
+        label_zeros: LabelZeros::Distinct,
+        dupe_labels: None,
+        lazy_syntax: None,
+        number_bits: Some(53),
+    },
+];
+
+impl SpecReport {
+    /// Analyzes parsed instructions, stopping at the first syntax error.
+    /// Instructions should be parsed with the default `ParseOptions`, so
+    /// that zero numbers and leading zeros are preserved.
+    #[must_use]
+    pub fn new<I: IntoIterator<Item = Result<ParsedInst, ParseError>>>(insts: I) -> Self {
+        let mut r = SpecReport {
+            version: Version::WS0_2,
+            extensions: Vec::new(),
+            empty_numbers: Vec::new(),
+            sign_only_numbers: Vec::new(),
+            empty_labels: Vec::new(),
+            leading_zero_labels: Vec::new(),
+            non_utf8_labels: Vec::new(),
+            dupe_labels: Vec::new(),
+            zero_dupe_labels: Vec::new(),
+            negative_copy_slide: Vec::new(),
+            max_number_bits: 0,
+            implicit_end: true,
+            trailing_junk: None,
+        };
+        let mut defs = HashSet::new();
+        let mut trimmed_defs = HashSet::new();
+        for inst in insts {
+            let inst = match inst {
+                Ok(inst) => inst,
+                Err(err) => {
+                    r.trailing_junk = Some(err);
+                    break;
+                }
+            };
+            let pos = inst.span.start;
+            let inst = inst.inst;
+            if let Some(v) = inst.version() {
+                r.version = r.version.max(v);
+            }
+            r.implicit_end = !matches!(inst, Inst::End | Inst::Jmp(_) | Inst::Ret);
+            if let Inst::Ext(ext) = inst {
+                if !r.extensions.contains(&ext.name) {
+                    r.extensions.push(ext.name);
+                }
+            }
+            if let Some(n) = inst.arg() {
+                if n.sign() == Sign::Empty {
+                    r.empty_numbers.push(pos);
+                } else if n.bit_len() == 0 {
+                    r.sign_only_numbers.push(pos);
+                }
+                let width = n.value().significant_bits() as usize;
+                r.max_number_bits = r.max_number_bits.max(width);
+                if matches!(inst, Inst::Copy(_) | Inst::Slide(_)) && *n.value() < 0 {
+                    r.negative_copy_slide.push(pos);
+                }
+            } else if let Some(l) = inst.label() {
+                if l.bit_len() == 0 {
+                    r.empty_labels.push(pos);
+                } else if l.has_leading_zeros() {
+                    r.leading_zero_labels.push(pos);
+                }
+                if l.as_utf8().is_none() {
+                    r.non_utf8_labels.push(pos);
+                }
+                if let Inst::Label(l) = &inst {
+                    let trimmed = Label::new(l.value().clone()).unwrap();
+                    if !defs.insert(l.clone()) {
+                        r.dupe_labels.push(pos);
+                    } else if !trimmed_defs.insert(trimmed) {
+                        r.zero_dupe_labels.push(pos);
+                    }
+                }
+            }
+        }
+        r
+    }
+
+    /// Returns the features that prevent an implementation from accepting
+    /// the program, or none, when it would be accepted.
+    #[must_use]
+    pub fn rejections(&self, imp: &Implementation) -> Vec<&'static str> {
+        let mut reasons = Vec::new();
+        if !self.extensions.is_empty() {
+            reasons.push("extension instructions");
+        }
+        if imp.zero_number != ZeroNumber::Empty && !self.empty_numbers.is_empty() {
+            reasons.push("numbers without sign");
+        }
+        if imp.zero_number == ZeroNumber::SignAndBits && !self.sign_only_numbers.is_empty() {
+            reasons.push("numbers without bits");
+        }
+        if !imp.empty_labels && !self.empty_labels.is_empty() {
+            reasons.push("labels without bits");
+        }
+        if imp.dupe_labels == Some(DupeLabels::Error) && self.has_dupe_labels(imp) {
+            reasons.push("duplicate labels");
+        }
+        if matches!(imp.number_bits, Some(bits) if self.max_number_bits > bits) {
+            reasons.push("numbers out of bounds");
+        }
+        if imp.lazy_syntax == Some(false) && self.trailing_junk.is_some() {
+            reasons.push("syntax errors");
+        }
+        reasons
+    }
+
+    /// Returns the features used by the program that an implementation
+    /// handles in an unknown way, so it may or may not accept the program.
+    #[must_use]
+    pub fn unknowns(&self, imp: &Implementation) -> Vec<&'static str> {
+        let mut unknowns = Vec::new();
+        if imp.dupe_labels.is_none() && self.has_dupe_labels(imp) {
+            unknowns.push("duplicate labels");
+        }
+        if imp.lazy_syntax.is_none() && self.trailing_junk.is_some() {
+            unknowns.push("syntax errors");
+        }
+        unknowns
+    }
+
+    /// Reports whether any labels are defined more than once under the
+    /// label rules of an implementation.
+    fn has_dupe_labels(&self, imp: &Implementation) -> bool {
+        !self.dupe_labels.is_empty()
+            || imp.label_zeros == LabelZeros::Ignored && !self.zero_dupe_labels.is_empty()
+    }
+
+    /// Formats the report as a JSON object.
+    #[must_use]
+    pub fn to_json(&self) -> String {
+        let mut s = String::new();
+        self.write_json(&mut s).unwrap();
+        s
+    }
+
+    fn write_json(&self, w: &mut String) -> fmt::Result {
+        writeln!(w, "{{")?;
+        writeln!(w, "  \"version\": \"{}\",", self.version)?;
+        writeln!(w, "  \"extensions\": [{}],", json_list(&self.extensions))?;
+        for (key, _, positions) in self.features() {
+            let positions = positions
+                .iter()
+                .map(|pos| format!("{{\"line\": {}, \"col\": {}}}", pos.line, pos.col));
+            let positions = positions.collect::<Vec<_>>().join(", ");
+            writeln!(w, "  \"{}\": [{}],", key, positions)?;
+        }
+        writeln!(w, "  \"max_number_bits\": {},", self.max_number_bits)?;
+        writeln!(w, "  \"implicit_end\": {},", self.implicit_end)?;
+        match &self.trailing_junk {
+            Some(err) => writeln!(
+                w,
+                "  \"trailing_junk\": {{\"line\": {}, \"col\": {}, \"error\": {}}},",
+                err.span.start.line,
+                err.span.start.col,
+                json_str(&err.to_string()),
+            )?,
+            None => writeln!(w, "  \"trailing_junk\": null,")?,
+        }
+        writeln!(w, "  \"implementations\": [")?;
+        for (i, imp) in IMPLEMENTATIONS.iter().enumerate() {
+            let reasons = self.rejections(imp);
+            let unknowns = self.unknowns(imp);
+            let accepted = match (reasons.is_empty(), unknowns.is_empty()) {
+                (false, _) => "false",
+                (true, false) => "null",
+                (true, true) => "true",
+            };
+            let comma = if i + 1 < IMPLEMENTATIONS.len() {
+                ","
+            } else {
+                ""
+            };
+            writeln!(
+                w,
+                "    {{\"name\": {}, \"accepted\": {}, \"rejections\": [{}], \"unknowns\": [{}]}}{}",
+                json_str(imp.name),
+                accepted,
+                json_list(&reasons),
+                json_list(&unknowns),
+                comma,
+            )?;
+        }
+        writeln!(w, "  ]")?;
+        writeln!(w, "}}")
+    }
+
+    /// Features located by position, with their JSON keys and display
+    /// names.
+    fn features(&self) -> [(&'static str, &'static str, &[Pos]); 8] {
+        [
+            ("empty_numbers", "numbers without sign", &self.empty_numbers),
+            (
+                "sign_only_numbers",
+                "numbers without bits",
+                &self.sign_only_numbers,
+            ),
+            ("empty_labels", "labels without bits", &self.empty_labels),
+            (
+                "leading_zero_labels",
+                "labels with leading zeros",
+                &self.leading_zero_labels,
+            ),
+            ("non_utf8_labels", "non-UTF-8 labels", &self.non_utf8_labels),
+            ("dupe_labels", "duplicate labels", &self.dupe_labels),
+            (
+                "zero_dupe_labels",
+                "duplicate labels ignoring leading zeros",
+                &self.zero_dupe_labels,
+            ),
+            (
+                "negative_copy_slide",
+                "negative copy or slide",
+                &self.negative_copy_slide,
+            ),
+        ]
+    }
+}
+
+/// Formats strings as the items of a JSON array.
+fn json_list(items: &[&str]) -> String {
+    let items = items.iter().map(|item| json_str(item));
+    items.collect::<Vec<_>>().join(", ")
+}
+
+/// Quotes and escapes a string for JSON.
+fn json_str(s: &str) -> String {
+    let mut q = String::with_capacity(s.len() + 2);
+    q.push('"');
+    for ch in s.chars() {
+        match ch {
+            '"' => q.push_str("\\\""),
+            '\\' => q.push_str("\\\\"),
+            '\n' => q.push_str("\\n"),
+            '\t' => q.push_str("\\t"),
+            '\r' => q.push_str("\\r"),
+            ch if ch.is_control() => write!(q, "\\u{:04x}", ch as u32).unwrap(),
+            ch => q.push(ch),
+        }
+    }
+    q.push('"');
+    q
+}
+
+impl fmt::Display for SpecReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "version: {}", self.version)?;
+        if self.extensions.is_empty() {
+            writeln!(f, "extensions: none")?;
+        } else {
+            writeln!(f, "extensions: {}", self.extensions.join(", "))?;
+        }
+        for (_, name, positions) in self.features() {
+            match positions.first() {
+                Some(first) => writeln!(f, "{}: {} (first at {})", name, positions.len(), first)?,
+                None => writeln!(f, "{}: none", name)?,
+            }
+        }
+        writeln!(f, "max number bits: {}", self.max_number_bits)?;
+        writeln!(
+            f,
+            "implicit end: {}",
+            if self.implicit_end { "yes" } else { "no" }
+        )?;
+        match &self.trailing_junk {
+            Some(err) => writeln!(f, "trailing junk: {}", err)?,
+            None => writeln!(f, "trailing junk: none")?,
+        }
+        writeln!(f, "implementations:")?;
+        for imp in IMPLEMENTATIONS {
+            let reasons = self.rejections(imp);
+            let unknowns = self.unknowns(imp);
+            if !reasons.is_empty() {
+                writeln!(f, "  {}: rejected ({})", imp.name, reasons.join(", "))?;
+            } else if !unknowns.is_empty() {
+                writeln!(f, "  {}: unknown ({})", imp.name, unknowns.join(", "))?;
+            } else {
+                writeln!(f, "  {}: accepted", imp.name)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::syntax::Parser;
+    use crate::token::{Lexer, Mapping};
+
+    #[test]
+    fn spec_report() {
+        let map = Mapping::new("S", "T", "L").unwrap();
+        // push; copy -1; 0:; 00:; 0:; jmp 1; <unknown LLT>
+        let src = "SSL STSTTL LSSSL LSSSSL LSSSL LSLTL LLT";
+        let r = SpecReport::new(Parser::new(Lexer::new(&src, map)));
+        assert_eq!(r.version, Version::WS0_3);
+        let pos = |offset, col| Pos {
+            offset,
+            line: 1,
+            col,
+        };
+        assert_eq!(r.empty_numbers, &[pos(0, 1)]);
+        assert_eq!(r.negative_copy_slide, &[pos(4, 5)]);
+        assert_eq!(
+            r.leading_zero_labels,
+            &[pos(11, 12), pos(17, 18), pos(24, 25)]
+        );
+        assert_eq!(r.dupe_labels, &[pos(24, 25)]);
+        assert_eq!(r.zero_dupe_labels, &[pos(17, 18)]);
+        assert_eq!(r.non_utf8_labels.len(), 4);
+        assert_eq!(r.max_number_bits, 1);
+        assert!(!r.implicit_end);
+        assert_eq!(
+            r.trailing_junk.as_ref().map(|err| err.span.start),
+            Some(pos(36, 37))
+        );
+
+        let rejections = IMPLEMENTATIONS
+            .iter()
+            .map(|imp| (imp.name, r.rejections(imp)))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            rejections,
+            &[
+                ("wspace", vec!["numbers without sign"]),
+                ("Nebula", vec!["duplicate labels", "syntax errors"]),
+                ("BlueSpace", vec!["syntax errors"]),
+                ("conrad", vec!["numbers without sign"]),
+                ("wsjq", vec![]),
+            ]
+        );
+        let unknowns = IMPLEMENTATIONS
+            .iter()
+            .map(|imp| (imp.name, r.unknowns(imp)))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            unknowns,
+            &[
+                ("wspace", vec![]),
+                ("Nebula", vec![]),
+                ("BlueSpace", vec![]),
+                ("conrad", vec!["duplicate labels", "syntax errors"]),
+                ("wsjq", vec!["duplicate labels", "syntax errors"]),
+            ]
+        );
+        assert!(r
+            .to_string()
+            .contains("  wsjq: unknown (duplicate labels, syntax errors)\n"));
+    }
+}
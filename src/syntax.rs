@@ -4,12 +4,14 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
+use crate::ext::{Extension, Extensions, Lookup};
 use crate::token::{
-    Lexer,
+    LexError, LexErrorKind, Lexeme, Pos, Span,
     Token::{self, *},
 };
+use clap::ArgEnum;
 use rug::{integer::Order, ops::NegAssign, Integer};
-use std::{fmt, str};
+use std::{error, fmt, str};
 pub use Inst::*;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -38,6 +40,7 @@ pub enum Inst {
     Printi,
     Readc,
     Readi,
+    Ext(Extension),
 }
 
 impl Inst {
@@ -101,6 +104,7 @@ impl Inst {
             Printi => &[T, L, S, T],
             Readc => &[T, L, T, S],
             Readi => &[T, L, T, T],
+            Ext(ext) => ext.opcode,
         }
     }
 
@@ -131,15 +135,19 @@ impl Inst {
             Printi => "printi",
             Readc => "readc",
             Readi => "readi",
+            Ext(ext) => ext.name,
         }
     }
 
+    /// Returns the version that introduced the instruction, or `None`,
+    /// when it is an extension outside of any standard version.
     #[inline]
     #[must_use]
-    pub const fn version(&self) -> Version {
+    pub const fn version(&self) -> Option<Version> {
         match self {
-            Copy(_) | Slide(_) => Version::WS0_3,
-            _ => Version::WS0_2,
+            Copy(_) | Slide(_) => Some(Version::WS0_3),
+            Ext(_) => None,
+            _ => Some(Version::WS0_2),
         }
     }
 }
@@ -158,9 +166,12 @@ impl fmt::Display for Inst {
     }
 }
 
-#[derive(Copy, Clone, PartialEq, Eq)]
+/// Standard Whitespace version.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ArgEnum)]
 pub enum Version {
+    #[clap(name = "0.2")]
     WS0_2,
+    #[clap(name = "0.3")]
     WS0_3,
 }
 
@@ -198,14 +209,29 @@ impl Int {
         }
     }
 
+    /// Constructs a number with its minimal encoding: a sign and the
+    /// significant bits. Zero has a positive sign and no bits.
+    #[must_use]
+    pub fn new<T: Into<Integer>>(val: T) -> Self {
+        let val = val.into();
+        let sign = if val < 0 { Sign::Neg } else { Sign::Pos };
+        let raw = RawUint::from_integer(&val);
+        Int { val, raw, sign }
+    }
+
     #[must_use]
     pub fn from_tokens<T: AsRef<[Token]>>(toks: T) -> Self {
         match toks.as_ref().split_first() {
             None => Int::empty(),
             Some((sign, toks)) => {
                 let raw = RawUint::from_tokens(toks);
-                let val = raw.to_integer();
-                let sign = if *sign == T { Sign::Neg } else { Sign::Pos };
+                let mut val = raw.to_integer();
+                let sign = if *sign == T {
+                    val.neg_assign();
+                    Sign::Neg
+                } else {
+                    Sign::Pos
+                };
                 Int { raw, val, sign }
             }
         }
@@ -219,9 +245,47 @@ impl Int {
         }
         self.raw.to_tokens(toks);
     }
+
+    #[inline]
+    #[must_use]
+    pub const fn value(&self) -> &Integer {
+        &self.val
+    }
+
+    #[inline]
+    #[must_use]
+    pub const fn sign(&self) -> Sign {
+        self.sign
+    }
+
+    /// Returns the number of bits in the encoding, excluding the sign.
+    #[inline]
+    #[must_use]
+    pub const fn bit_len(&self) -> usize {
+        self.raw.len()
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn has_leading_zeros(&self) -> bool {
+        self.raw.has_leading_zeros()
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn leading_zeros(&self) -> usize {
+        self.raw.leading_zeros()
+    }
+
+    /// Reports whether the number is zero with a negative sign.
+    #[inline]
+    #[must_use]
+    pub fn is_negative_zero(&self) -> bool {
+        self.sign == Sign::Neg && self.raw.significant_bits() == 0
+    }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Label {
     val: Integer,
     raw: RawUint,
@@ -237,6 +301,18 @@ impl Label {
         }
     }
 
+    /// Constructs a label with its minimal encoding: the significant
+    /// bits. Zero has no bits. Returns `None` for negative values.
+    #[must_use]
+    pub fn new<T: Into<Integer>>(val: T) -> Option<Self> {
+        let val = val.into();
+        if val < 0 {
+            return None;
+        }
+        let raw = RawUint::from_integer(&val);
+        Some(Label { val, raw })
+    }
+
     #[must_use]
     pub fn as_utf8(&self) -> Option<&str> {
         if self.raw.len() % 8 == 0 {
@@ -258,9 +334,40 @@ impl Label {
     pub fn to_tokens(&self, v: &mut Vec<Token>) {
         self.raw.to_tokens(v);
     }
+
+    #[inline]
+    #[must_use]
+    pub const fn value(&self) -> &Integer {
+        &self.val
+    }
+
+    /// Returns the number of bits in the encoding.
+    #[inline]
+    #[must_use]
+    pub const fn bit_len(&self) -> usize {
+        self.raw.len()
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn has_leading_zeros(&self) -> bool {
+        self.raw.has_leading_zeros()
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn leading_zeros(&self) -> usize {
+        self.raw.leading_zeros()
+    }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+impl fmt::Display for Label {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.val)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 struct RawUint {
     buf: Vec<u8>,
     len: usize,
@@ -299,6 +406,15 @@ impl RawUint {
         }
     }
 
+    /// Encodes the magnitude of an integer without leading zeros.
+    #[must_use]
+    fn from_integer(val: &Integer) -> Self {
+        RawUint {
+            buf: val.to_digits(Order::MsfBe),
+            len: val.significant_bits() as usize,
+        }
+    }
+
     fn to_tokens(&self, toks: &mut Vec<Token>) {
         toks.reserve(self.len + 1);
         for i in 0..self.len {
@@ -310,20 +426,24 @@ impl RawUint {
     #[inline]
     #[must_use]
     fn bit(&self, i: usize) -> bool {
+        // Bits are right-aligned in buf
+        let i = i + self.buf.len() * 8 - self.len;
         (self.buf[i / 8] >> (7 - i % 8)) & 1 == 1
     }
 
     #[inline]
     #[must_use]
     fn has_leading_zeros(&self) -> bool {
-        self.len != 0 && (self.buf[0] >> self.len % 8 == 0)
+        self.len != 0 && !self.bit(0)
     }
 
     #[must_use]
     fn leading_zeros(&self) -> usize {
+        // Padding bits before the first bit in buf
+        let pad = self.buf.len() * 8 - self.len;
         for (i, b) in self.buf.iter().enumerate() {
             if *b != 0 {
-                return i * 8 + b.leading_zeros() as usize - self.len % 8;
+                return i * 8 + b.leading_zeros() as usize - pad;
             }
         }
         self.len
@@ -348,121 +468,284 @@ impl RawUint {
     }
 }
 
-pub struct Parser<'a> {
-    lex: Lexer<'a>,
+pub struct Parser<L> {
+    lex: L,
+    opts: ParseOptions,
     tok_buf: Vec<Token>,
-    toks: usize,
-    comments: Option<Vec<String>>,
+    inst_toks: Vec<Token>,
+    comments: Option<Vec<Vec<u8>>>,
+    start: Pos,
+    end: Pos,
+    lex_err: Option<LexError>,
+    done: bool,
 }
 
-impl<'a> Parser<'a> {
+/// Instruction with the span of source it was parsed from and the
+/// comments within it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParsedInst {
+    pub inst: Inst,
+    pub span: Span,
+    /// Comment text preceding each token of the instruction, or `None`,
+    /// when it has no comments
+    pub comments: Option<Vec<Vec<u8>>>,
+}
+
+impl ParsedInst {
+    /// Concatenates the comments of all tokens.
+    #[must_use]
+    pub fn comment(&self) -> Option<Vec<u8>> {
+        self.comments.as_ref().map(|comments| comments.concat())
+    }
+}
+
+/// Rules for syntax on which implementations disagree.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseOptions {
+    pub zero_number: ZeroNumber,
+    pub label_zeros: LabelZeros,
+    /// Extension instructions to recognize
+    pub extensions: Extensions,
+}
+
+/// Forms accepted for a number argument of zero.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ArgEnum)]
+pub enum ZeroNumber {
+    /// Sign and at least one bit are required (conrad)
+    SignAndBits,
+    /// Sign is required, but bits may be omitted (wspace)
+    Sign,
+    /// Sign and bits may both be omitted (Nebula)
+    Empty,
+}
+
+/// How leading zeros in labels are treated.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ArgEnum)]
+pub enum LabelZeros {
+    /// Labels differing only by leading zeros are distinct (wspace)
+    Distinct,
+    /// Leading zeros are ignored and dropped from parsed labels (Nebula)
+    Ignored,
+}
+
+impl const Default for ParseOptions {
+    /// Accepts all zero numbers and keeps labels distinct, so that any
+    /// program parses without loss.
+    #[inline]
+    fn default() -> Self {
+        ParseOptions {
+            zero_number: ZeroNumber::Empty,
+            label_zeros: LabelZeros::Distinct,
+            extensions: Extensions::new(),
+        }
+    }
+}
+
+impl<L> Parser<L> {
+    #[inline]
+    #[must_use]
+    pub const fn new(lex: L) -> Self {
+        Parser::with_options(lex, ParseOptions::default())
+    }
+
     #[inline]
     #[must_use]
-    pub const fn new(lex: Lexer<'a>) -> Self {
+    pub const fn with_options(lex: L, opts: ParseOptions) -> Self {
         Parser {
             lex,
+            opts,
             tok_buf: vec![],
-            toks: 0,
+            inst_toks: vec![],
             comments: None,
+            start: Pos::new(),
+            end: Pos::new(),
+            lex_err: None,
+            done: false,
         }
     }
 
+    #[inline]
+    #[must_use]
+    pub fn lexer_mut(&mut self) -> &mut L {
+        &mut self.lex
+    }
+}
+
+impl<'a, L: Iterator<Item = Result<Lexeme<'a>, LexError>>> Parser<L> {
     #[must_use]
     fn next_token(&mut self) -> Option<Token> {
-        let (tok, comment) = self.lex.next()?;
-        if comment.len() != 0 {
-            if self.comments == None {
-                self.comments = Some(vec![String::new(); self.toks]);
+        let lex = match self.lex.next()? {
+            Ok(lex) => lex,
+            Err(err) => {
+                self.lex_err = Some(err);
+                return None;
             }
-            self.comments.as_mut().unwrap().push(comment.to_string());
+        };
+        // Comments are rare, so only allocate once one is encountered
+        if lex.comment.len() != 0 && self.comments == None {
+            self.comments = Some(vec![Vec::new(); self.inst_toks.len()]);
+        }
+        if let Some(comments) = &mut self.comments {
+            comments.push(lex.comment.into_owned());
+        }
+        if self.inst_toks.is_empty() {
+            self.start = lex.span.start;
+        }
+        self.end = lex.span.end;
+        self.inst_toks.push(lex.tok);
+        Some(lex.tok)
+    }
+
+    #[inline]
+    fn next_opcode_token(&mut self) -> Result<Token, ParseError> {
+        self.next_token()
+            .ok_or_else(|| self.error(ParseErrorKind::IncompleteOpcode))
+    }
+
+    #[inline]
+    #[must_use]
+    fn unknown_opcode(&self) -> ParseError {
+        self.error(ParseErrorKind::UnknownOpcode)
+    }
+
+    #[must_use]
+    fn error(&self, kind: ParseErrorKind) -> ParseError {
+        ParseError {
+            kind,
+            toks: self.inst_toks.clone(),
+            span: Span::new(self.start, self.end),
         }
-        Some(tok)
     }
 }
 
-impl Iterator for Parser<'_> {
-    type Item = Inst;
+impl<'a, L: Iterator<Item = Result<Lexeme<'a>, LexError>>> Iterator for Parser<L> {
+    type Item = Result<ParsedInst, ParseError>;
 
     #[must_use]
-    fn next(&mut self) -> Option<Inst> {
-        self.toks = 0;
-        match self.next_token()? {
+    fn next(&mut self) -> Option<Self::Item> {
+        // Syntax is not self-synchronizing, so stop at the first error
+        if self.done {
+            return None;
+        }
+        self.inst_toks.clear();
+        self.comments = None;
+        let res = self.next_token().map(|tok| self.parse_inst(tok));
+        // Encoding errors supersede the syntax errors they cause
+        if let Some(err) = self.lex_err.take() {
+            self.done = true;
+            if self.inst_toks.is_empty() {
+                self.start = err.pos;
+            }
+            self.end = err.pos;
+            return Some(Err(self.error(ParseErrorKind::Lex(err.kind))));
+        }
+        match res {
+            Some(Ok(inst)) => Some(Ok(ParsedInst {
+                inst,
+                span: Span::new(self.start, self.end),
+                comments: self.comments.take(),
+            })),
+            Some(Err(err)) => {
+                self.done = true;
+                Some(Err(err))
+            }
+            None => {
+                self.done = true;
+                None
+            }
+        }
+    }
+}
+
+impl<'a, L: Iterator<Item = Result<Lexeme<'a>, LexError>>> Parser<L> {
+    fn parse_inst(&mut self, first: Token) -> Result<Inst, ParseError> {
+        match first {
             // Stack manipulation
-            S => match self.next_token()? {
-                S => Some(Push(self.parse_int()?)),
-                T => match self.next_token()? {
-                    S => Some(Copy(self.parse_int()?)),
-                    T => None,
-                    L => Some(Slide(self.parse_int()?)),
+            S => match self.next_opcode_token()? {
+                S => Ok(Push(self.parse_int()?)),
+                T => match self.next_opcode_token()? {
+                    S => Ok(Copy(self.parse_int()?)),
+                    T => self.parse_ext(),
+                    L => Ok(Slide(self.parse_int()?)),
                 },
-                L => match self.next_token()? {
-                    S => Some(Dup),
-                    T => Some(Swap),
-                    L => Some(Drop),
+                L => match self.next_opcode_token()? {
+                    S => Ok(Dup),
+                    T => Ok(Swap),
+                    L => Ok(Drop),
                 },
             },
 
-            T => match self.next_token()? {
+            T => match self.next_opcode_token()? {
                 // Arithmetic
-                S => match self.next_token()? {
-                    S => match self.next_token()? {
-                        S => Some(Add),
-                        T => Some(Sub),
-                        L => Some(Mul),
+                S => match self.next_opcode_token()? {
+                    S => match self.next_opcode_token()? {
+                        S => Ok(Add),
+                        T => Ok(Sub),
+                        L => Ok(Mul),
                     },
-                    T => match self.next_token()? {
-                        S => Some(Div),
-                        T => Some(Mod),
-                        L => None,
+                    T => match self.next_opcode_token()? {
+                        S => Ok(Div),
+                        T => Ok(Mod),
+                        L => self.parse_ext(),
                     },
-                    L => None,
+                    L => self.parse_ext(),
                 },
 
                 // Heap access
-                T => match self.next_token()? {
-                    S => Some(Store),
-                    T => Some(Retrieve),
-                    L => None,
+                T => match self.next_opcode_token()? {
+                    S => Ok(Store),
+                    T => Ok(Retrieve),
+                    L => self.parse_ext(),
                 },
 
                 // I/O
-                L => match self.next_token()? {
-                    S => match self.next_token()? {
-                        S => Some(Printc),
-                        T => Some(Printi),
-                        L => None,
+                L => match self.next_opcode_token()? {
+                    S => match self.next_opcode_token()? {
+                        S => Ok(Printc),
+                        T => Ok(Printi),
+                        L => self.parse_ext(),
                     },
-                    T => match self.next_token()? {
-                        S => Some(Readc),
-                        T => Some(Readi),
-                        L => None,
+                    T => match self.next_opcode_token()? {
+                        S => Ok(Readc),
+                        T => Ok(Readi),
+                        L => self.parse_ext(),
                     },
-                    L => None,
+                    L => self.parse_ext(),
                 },
             },
 
             // Control flow
-            L => match self.next_token()? {
-                S => match self.next_token()? {
-                    S => Some(Label(self.parse_label()?)),
-                    T => Some(Call(self.parse_label()?)),
-                    L => Some(Jmp(self.parse_label()?)),
+            L => match self.next_opcode_token()? {
+                S => match self.next_opcode_token()? {
+                    S => Ok(Label(self.parse_label()?)),
+                    T => Ok(Call(self.parse_label()?)),
+                    L => Ok(Jmp(self.parse_label()?)),
                 },
-                T => match self.next_token()? {
-                    S => Some(Jz(self.parse_label()?)),
-                    T => Some(Jn(self.parse_label()?)),
-                    L => Some(Ret),
+                T => match self.next_opcode_token()? {
+                    S => Ok(Jz(self.parse_label()?)),
+                    T => Ok(Jn(self.parse_label()?)),
+                    L => Ok(Ret),
                 },
-                L => match self.next_token()? {
-                    L => Some(End),
-                    _ => None,
+                L => match self.next_opcode_token()? {
+                    L => Ok(End),
+                    _ => self.parse_ext(),
                 },
             },
         }
     }
-}
 
-impl Parser<'_> {
+    /// Continues parsing an opcode that starts with a free prefix as an
+    /// extension.
+    fn parse_ext(&mut self) -> Result<Inst, ParseError> {
+        loop {
+            match self.opts.extensions.lookup(&self.inst_toks) {
+                Lookup::Match(ext) => return Ok(Ext(ext)),
+                Lookup::Prefix => self.next_opcode_token()?,
+                Lookup::None => return Err(self.unknown_opcode()),
+            };
+        }
+    }
+
     #[must_use]
     fn parse_uint(&mut self) -> Option<RawUint> {
         self.tok_buf.clear();
@@ -476,26 +759,82 @@ impl Parser<'_> {
         Some(RawUint::from_tokens(&self.tok_buf))
     }
 
-    #[must_use]
-    fn parse_int(&mut self) -> Option<Int> {
-        let sign = match self.next_token()? {
+    fn parse_int(&mut self) -> Result<Int, ParseError> {
+        let unterminated = |p: &Self| p.error(ParseErrorKind::UnterminatedInt);
+        let sign = match self.next_token().ok_or_else(|| unterminated(self))? {
             S => Sign::Pos,
             T => Sign::Neg,
-            L => return Some(Int::empty()),
+            L if self.opts.zero_number == ZeroNumber::Empty => return Ok(Int::empty()),
+            L => return Err(self.error(ParseErrorKind::EmptyInt)),
         };
-        let raw = self.parse_uint()?;
+        let raw = self.parse_uint().ok_or_else(|| unterminated(self))?;
+        if raw.len() == 0 && self.opts.zero_number == ZeroNumber::SignAndBits {
+            return Err(self.error(ParseErrorKind::SignOnlyInt));
+        }
         let mut val = raw.to_integer();
         if sign == Sign::Neg {
             val.neg_assign();
         }
-        Some(Int { val, raw, sign })
+        Ok(Int { val, raw, sign })
     }
 
-    #[must_use]
-    fn parse_label(&mut self) -> Option<Label> {
-        let raw = self.parse_uint()?;
+    fn parse_label(&mut self) -> Result<Label, ParseError> {
+        let mut raw = self
+            .parse_uint()
+            .ok_or_else(|| self.error(ParseErrorKind::UnterminatedLabel))?;
+        if self.opts.label_zeros == LabelZeros::Ignored {
+            let zeros = self.tok_buf.iter().take_while(|&&tok| tok == S).count();
+            raw = RawUint::from_tokens(&self.tok_buf[zeros..]);
+        }
         let val = raw.to_integer();
-        Some(Label { val, raw })
+        Ok(Label { val, raw })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub kind: ParseErrorKind,
+    /// Tokens of the instruction consumed up to the error
+    pub toks: Vec<Token>,
+    pub span: Span,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ParseErrorKind {
+    /// Tokens do not form the prefix of any instruction
+    UnknownOpcode,
+    /// EOF reached within an instruction opcode
+    IncompleteOpcode,
+    /// EOF reached before a number argument was terminated by L
+    UnterminatedInt,
+    /// EOF reached before a label argument was terminated by L
+    UnterminatedLabel,
+    /// Number argument without a sign, with `ZeroNumber::Sign` or
+    /// `ZeroNumber::SignAndBits`
+    EmptyInt,
+    /// Number argument with a sign, but no bits, with
+    /// `ZeroNumber::SignAndBits`
+    SignOnlyInt,
+    /// Lexer error, such as invalid UTF-8
+    Lex(LexErrorKind),
+}
+
+impl error::Error for ParseError {}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use ParseErrorKind::*;
+        match self.kind {
+            UnknownOpcode => write!(f, "unknown instruction ")?,
+            IncompleteOpcode => write!(f, "incomplete instruction ")?,
+            UnterminatedInt => write!(f, "unterminated number in ")?,
+            UnterminatedLabel => write!(f, "unterminated label in ")?,
+            EmptyInt => write!(f, "number without sign in ")?,
+            SignOnlyInt => write!(f, "number without bits in ")?,
+            Lex(kind) => return write!(f, "{} at {}", kind, self.span.end),
+        }
+        self.toks.iter().try_for_each(|tok| write!(f, "{}", tok))?;
+        write!(f, " at {}", self.span.start)
     }
 }
 
@@ -503,12 +842,16 @@ impl Parser<'_> {
 mod test {
     use super::*;
     use crate::token::test::TUTORIAL_SRC;
-    use crate::token::Mapping;
+    use crate::token::{Lexer, Mapping};
 
     #[test]
     fn parse_tutorial() {
         let p = Parser::new(Lexer::new(&TUTORIAL_SRC, Mapping::default()));
-        let insts = p.collect::<Vec<_>>();
+        let insts = p.collect::<Result<Vec<_>, _>>().unwrap();
+        let pos = |offset, line, col| Pos { offset, line, col };
+        assert_eq!(insts[0].span, Span::new(pos(0, 1, 1), pos(5, 2, 1)));
+        assert_eq!(insts[1].span, Span::new(pos(5, 2, 1), pos(17, 4, 1)));
+        let insts = insts.into_iter().map(|p| p.inst).collect::<Vec<_>>();
         assert_eq!(
             insts,
             &[
@@ -531,4 +874,154 @@ mod test {
             ]
         );
     }
+
+    #[test]
+    fn parse_comments() {
+        let src = "a  b\t\nc\n\n\n\n\n\nd";
+        let insts = Parser::new(Lexer::new(&src, Mapping::default()))
+            .map(|inst| inst.unwrap().comments)
+            .collect::<Vec<_>>();
+        let comments = |c: &[&str]| Some(c.iter().map(|c| c.as_bytes().to_vec()).collect());
+        assert_eq!(
+            insts,
+            &[
+                comments(&["a", "", "b", ""]),
+                comments(&["c", "", ""]),
+                None
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_errors() {
+        use ParseErrorKind::*;
+        let pos = |offset, line, col| Pos { offset, line, col };
+        let tests = [
+            ("unknown_instruction_stt.ws", (UnknownOpcode, vec![S, T, T])),
+            (
+                "unknown_instruction_tstl.ws",
+                (UnknownOpcode, vec![T, S, T, L]),
+            ),
+            ("unknown_instruction_tsl.ws", (UnknownOpcode, vec![T, S, L])),
+            ("unknown_instruction_ttl.ws", (UnknownOpcode, vec![T, T, L])),
+            (
+                "unknown_instruction_tlsl.ws",
+                (UnknownOpcode, vec![T, L, S, L]),
+            ),
+            (
+                "unknown_instruction_tltl.ws",
+                (UnknownOpcode, vec![T, L, T, L]),
+            ),
+            ("unknown_instruction_tll.ws", (UnknownOpcode, vec![T, L, L])),
+            ("unknown_instruction_lls.ws", (UnknownOpcode, vec![L, L, S])),
+            ("unknown_instruction_llt.ws", (UnknownOpcode, vec![L, L, T])),
+            ("unterminated_push.ws", (UnterminatedInt, vec![S, S])),
+            ("unterminated_copy.ws", (UnterminatedInt, vec![S, T, S])),
+            ("unterminated_slide.ws", (UnterminatedInt, vec![S, T, L])),
+            ("unterminated_label.ws", (UnterminatedLabel, vec![L, S, S])),
+            ("unterminated_call.ws", (UnterminatedLabel, vec![L, S, T])),
+            ("unterminated_jmp.ws", (UnterminatedLabel, vec![L, S, L])),
+            ("unterminated_jz.ws", (UnterminatedLabel, vec![L, T, S])),
+            ("unterminated_jn.ws", (UnterminatedLabel, vec![L, T, T])),
+        ];
+        for (file, expect) in tests {
+            let path = format!("{}/tests/{}", env!("CARGO_MANIFEST_DIR"), file);
+            let src = std::fs::read(path).unwrap();
+            let mut p = Parser::new(Lexer::new(&src, Mapping::default()));
+            let err = p.find_map(Result::err).map(|err| (err.kind, err.toks));
+            assert_eq!(err, Some(expect), "{}", file);
+            assert_eq!(p.next(), None);
+        }
+        let mut p = Parser::new(Lexer::new(b"\t\n", Mapping::default()));
+        let err = p.next().unwrap().unwrap_err();
+        assert_eq!((err.kind, err.toks), (IncompleteOpcode, vec![T, L]));
+        assert_eq!(err.span, Span::new(Pos::new(), pos(2, 2, 1)));
+    }
+
+    #[test]
+    fn parse_options() {
+        use ParseErrorKind::*;
+        let parse = |file, opts| {
+            let path = format!("{}/tests/{}", env!("CARGO_MANIFEST_DIR"), file);
+            let src = std::fs::read(path).unwrap();
+            Parser::with_options(Lexer::new(&src, Mapping::default()), opts)
+                .map(|inst| inst.map(|inst| inst.inst).map_err(|err| err.kind))
+                .collect::<Result<Vec<_>, _>>()
+        };
+        let zero = |zero_number| ParseOptions {
+            zero_number,
+            ..ParseOptions::default()
+        };
+        let empty = Push(Int::from_tokens(&[]));
+        let sign_only = Push(Int::from_tokens(&[S]));
+        assert_eq!(
+            parse("push_zero_empty.ws", zero(ZeroNumber::Empty)).unwrap()[0],
+            empty
+        );
+        assert_eq!(
+            parse("push_zero_empty.ws", zero(ZeroNumber::Sign)),
+            Err(EmptyInt)
+        );
+        assert_eq!(
+            parse("push_zero_sign_only.ws", zero(ZeroNumber::Sign)).unwrap()[0],
+            sign_only
+        );
+        assert_eq!(
+            parse("push_zero_sign_only.ws", zero(ZeroNumber::SignAndBits)),
+            Err(SignOnlyInt)
+        );
+
+        let labels = |label_zeros| {
+            let opts = ParseOptions {
+                label_zeros,
+                ..ParseOptions::default()
+            };
+            parse("leading_zero_labels.ws", opts)
+                .unwrap()
+                .into_iter()
+                .filter_map(|inst| inst.label().cloned())
+                .collect::<Vec<_>>()
+        };
+        let (t, st) = (Label::from_tokens(&[T]), Label::from_tokens(&[S, T]));
+        assert_eq!(
+            labels(LabelZeros::Distinct),
+            &[t.clone(), st.clone(), t.clone(), st]
+        );
+        assert_eq!(
+            labels(LabelZeros::Ignored),
+            &[t.clone(), t.clone(), t.clone(), t]
+        );
+    }
+
+    #[test]
+    fn int_label_accessors() {
+        let toks = |n: &Int| {
+            let mut v = Vec::new();
+            n.to_tokens(&mut v);
+            v
+        };
+        assert_eq!(toks(&Int::new(42)), &[S, T, S, T, S, T, S, L]);
+        assert_eq!(toks(&Int::new(-5)), &[T, T, S, T, L]);
+        assert_eq!(toks(&Int::new(0)), &[S, L]);
+        assert_eq!(Int::new(Integer::from(-5)), Int::from_tokens(&[T, T, S, T]));
+
+        let n = Int::from_tokens(&[T, S, T]);
+        assert_eq!(
+            (n.value().to_i32(), n.sign(), n.bit_len()),
+            (Some(-1), Sign::Neg, 2)
+        );
+        assert!(n.has_leading_zeros() && !n.is_negative_zero());
+        assert_eq!(n.leading_zeros(), 1);
+        assert!(Int::from_tokens(&[T, S]).is_negative_zero());
+
+        assert_eq!(Label::new(-1), None);
+        assert_eq!(Label::new(0).unwrap().bit_len(), 0);
+        let l = Label::from_tokens(&[S, S, S, S, S, S, S, S, S, T]);
+        assert!(l.has_leading_zeros());
+        assert_eq!(l.leading_zeros(), 9);
+        let l = Label::from_tokens(&[T, S, S, S, S, S, S, S, S]);
+        assert!(!l.has_leading_zeros());
+        assert_eq!(l.leading_zeros(), 0);
+        assert_eq!(Label::new(256), Some(l));
+    }
 }
@@ -4,7 +4,15 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
-use std::{char, error, fmt, str};
+use clap::ArgEnum;
+use std::{
+    borrow::Cow,
+    char,
+    cmp::Reverse,
+    error, fmt,
+    io::{self, Read},
+    mem, str,
+};
 use Token::*;
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
@@ -14,54 +22,528 @@ pub enum Token {
     L,
 }
 
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            S => write!(f, "S"),
+            T => write!(f, "T"),
+            L => write!(f, "L"),
+        }
+    }
+}
+
+/// Location in the source, as a byte offset and 1-based line and column.
+/// Columns count characters, not bytes.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Pos {
+    pub offset: usize,
+    pub line: usize,
+    pub col: usize,
+}
+
+impl Pos {
+    #[inline]
+    #[must_use]
+    pub const fn new() -> Self {
+        Pos {
+            offset: 0,
+            line: 1,
+            col: 1,
+        }
+    }
+
+    #[inline]
+    fn advance(&mut self, ch: char, size: usize) {
+        self.offset += size;
+        if ch == '\n' {
+            self.line += 1;
+            self.col = 1;
+        } else {
+            self.col += 1;
+        }
+    }
+}
+
+impl const Default for Pos {
+    #[inline]
+    fn default() -> Self {
+        Pos::new()
+    }
+}
+
+impl fmt::Display for Pos {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.col)
+    }
+}
+
+/// Half-open range of source from `start` to `end`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Span {
+    pub start: Pos,
+    pub end: Pos,
+}
+
+impl Span {
+    #[inline]
+    #[must_use]
+    pub const fn new(start: Pos, end: Pos) -> Self {
+        Span { start, end }
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.start, self.end)
+    }
+}
+
+/// Token with its span and the comment text preceding it. Comments are
+/// valid UTF-8, unless lexed with `Encoding::Bytes`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lexeme<'a> {
+    pub tok: Token,
+    pub span: Span,
+    pub comment: Cow<'a, [u8]>,
+    /// Source text of the token, which is empty for bit-packed tokens
+    pub text: Cow<'a, [u8]>,
+}
+
+/// Lexer that can return the source after its last token, so that
+/// trailing comments are not lost.
+pub trait Remainder {
+    /// Returns the source after the last token, including any source
+    /// that could not be lexed. It should be called once the lexer is
+    /// exhausted.
+    fn remainder(&mut self) -> Result<Vec<u8>, LexError>;
+}
+
 pub struct Lexer<'a> {
     src: &'a [u8],
-    i: usize,
-    map: Mapping,
+    pos: Pos,
+    /// Offset after the last token
+    tok_end: usize,
+    scanner: Scanner,
+    err: Option<LexError>,
+    done: bool,
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct LexOptions {
+    pub encoding: Encoding,
+    pub utf8_check: Utf8Check,
+    pub line_ending: LineEnding,
+}
+
+/// Encoding of the source.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ArgEnum)]
+pub enum Encoding {
+    /// UTF-8 text (wspace)
+    Utf8,
+    /// Raw bytes, with the mapping matched as Latin-1
+    Bytes,
+}
+
+impl const Default for Encoding {
+    #[inline]
+    fn default() -> Self {
+        Encoding::Utf8
+    }
+}
+
+/// When invalid UTF-8 is reported.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ArgEnum)]
+pub enum Utf8Check {
+    /// Report when the invalid sequence is reached (wspace)
+    Lazy,
+    /// Reject the whole source before lexing (Nebula, BlueSpace)
+    Eager,
+}
+
+impl const Default for Utf8Check {
+    #[inline]
+    fn default() -> Self {
+        Utf8Check::Lazy
+    }
+}
+
+/// Line breaks recognized as L, when L is mapped to LF.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ArgEnum)]
+pub enum LineEnding {
+    /// LF only (wspace)
+    Lf,
+    /// LF and CRLF
+    Crlf,
+    /// LF, CRLF, and CR
+    Cr,
+}
+
+impl const Default for LineEnding {
+    #[inline]
+    fn default() -> Self {
+        LineEnding::Lf
+    }
 }
 
 impl<'a> Lexer<'a> {
     #[inline]
     pub fn new<B: AsRef<[u8]>>(src: &'a B, map: Mapping) -> Self {
+        Lexer::with_options(src, map, LexOptions::default())
+    }
+
+    pub fn with_options<B: AsRef<[u8]>>(src: &'a B, map: Mapping, opts: LexOptions) -> Self {
+        let src = src.as_ref();
+        let mut err = None;
+        if opts.encoding == Encoding::Utf8 && opts.utf8_check == Utf8Check::Eager {
+            if let Err(e) = str::from_utf8(src) {
+                let mut pos = Pos::new();
+                // SAFETY: checked as UTF-8 up to this point
+                let valid = unsafe { str::from_utf8_unchecked(&src[..e.valid_up_to()]) };
+                valid.chars().for_each(|ch| pos.advance(ch, ch.len_utf8()));
+                err = Some(LexError {
+                    kind: LexErrorKind::InvalidUtf8,
+                    pos,
+                });
+            }
+        }
         Lexer {
-            src: src.as_ref(),
-            i: 0,
-            map,
+            src,
+            pos: Pos::new(),
+            tok_end: 0,
+            scanner: Scanner::new(map, opts),
+            err,
+            done: false,
         }
     }
 }
 
 impl<'a> Iterator for Lexer<'a> {
-    type Item = (Token, &'a str);
+    type Item = Result<Lexeme<'a>, LexError>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let start = self.i;
-        while self.i < self.src.len() {
-            // Lazily decode UTF-8
-            let (ch, size) = bstr::decode_utf8(&self.src[self.i..]);
-            self.i += size;
-            if let Some(tok) = self.map.from_char(ch.expect("invalid UTF-8")) {
-                let comment = &self.src[start..self.i - size];
-                // SAFETY: already checked as UTF-8
-                return Some((tok, unsafe { str::from_utf8_unchecked(comment) }));
+        if self.done {
+            return None;
+        }
+        if let Some(err) = self.err.take() {
+            self.done = true;
+            return Some(Err(err));
+        }
+        let start = self.pos.offset;
+        loop {
+            match self.scanner.scan(&self.src[self.pos.offset..], true) {
+                Scan::Token(tok, size) => {
+                    let tok_start = self.pos;
+                    self.scanner.advance(&mut self.pos, tok, size);
+                    self.tok_end = self.pos.offset;
+                    return Some(Ok(Lexeme {
+                        tok,
+                        span: Span::new(tok_start, self.pos),
+                        comment: Cow::Borrowed(&self.src[start..tok_start.offset]),
+                        text: Cow::Borrowed(&self.src[tok_start.offset..self.pos.offset]),
+                    }));
+                }
+                Scan::Char(ch, size) => self.pos.advance(ch, size),
+                Scan::Incomplete => break,
+                Scan::InvalidUtf8 => {
+                    self.done = true;
+                    return Some(Err(LexError {
+                        kind: LexErrorKind::InvalidUtf8,
+                        pos: self.pos,
+                    }));
+                }
+            }
+        }
+        self.done = true;
+        None
+    }
+}
+
+impl Remainder for Lexer<'_> {
+    #[inline]
+    fn remainder(&mut self) -> Result<Vec<u8>, LexError> {
+        Ok(self.src[self.tok_end..].to_vec())
+    }
+}
+
+/// Lexer that reads its source incrementally. Invalid UTF-8 is always
+/// checked lazily.
+pub struct ReadLexer<R> {
+    r: R,
+    buf: Vec<u8>,
+    /// Start of the comment of the current lexeme. Source before it is
+    /// discarded when the buffer is refilled.
+    start: usize,
+    i: usize,
+    pos: Pos,
+    scanner: Scanner,
+    eof: bool,
+    done: bool,
+}
+
+impl<R: Read> ReadLexer<R> {
+    const CHUNK_SIZE: usize = 8192;
+
+    #[inline]
+    pub fn new(r: R, map: Mapping, opts: LexOptions) -> Self {
+        ReadLexer {
+            r,
+            buf: Vec::new(),
+            start: 0,
+            i: 0,
+            pos: Pos::new(),
+            scanner: Scanner::new(map, opts),
+            eof: false,
+            done: false,
+        }
+    }
+
+    fn fill(&mut self) -> io::Result<()> {
+        self.buf.drain(..self.start);
+        self.i -= self.start;
+        self.start = 0;
+        let len = self.buf.len();
+        self.buf.resize(len + Self::CHUNK_SIZE, 0);
+        loop {
+            match self.r.read(&mut self.buf[len..]) {
+                Ok(n) => {
+                    self.buf.truncate(len + n);
+                    self.eof = n == 0;
+                    return Ok(());
+                }
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => {
+                    self.buf.truncate(len);
+                    return Err(err);
+                }
+            }
+        }
+    }
+}
+
+impl<R: Read> Iterator for ReadLexer<R> {
+    type Item = Result<Lexeme<'static>, LexError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        // Discard the previous lexeme
+        self.start = self.i;
+        loop {
+            match self.scanner.scan(&self.buf[self.i..], self.eof) {
+                Scan::Token(tok, size) => {
+                    let tok_start = self.pos;
+                    self.scanner.advance(&mut self.pos, tok, size);
+                    let comment = self.buf[self.start..self.i].to_vec();
+                    let text = token_text(&self.buf[self.i..self.i + size]);
+                    self.i += size;
+                    return Some(Ok(Lexeme {
+                        tok,
+                        span: Span::new(tok_start, self.pos),
+                        comment: Cow::Owned(comment),
+                        text,
+                    }));
+                }
+                Scan::Char(ch, size) => {
+                    self.pos.advance(ch, size);
+                    self.i += size;
+                }
+                Scan::Incomplete if !self.eof => {
+                    if let Err(err) = self.fill() {
+                        self.done = true;
+                        return Some(Err(LexError {
+                            kind: LexErrorKind::Io(err.kind()),
+                            pos: self.pos,
+                        }));
+                    }
+                }
+                Scan::Incomplete => break,
+                Scan::InvalidUtf8 => {
+                    self.done = true;
+                    return Some(Err(LexError {
+                        kind: LexErrorKind::InvalidUtf8,
+                        pos: self.pos,
+                    }));
+                }
             }
         }
+        self.done = true;
         None
     }
 }
 
+/// Every byte value, so that single-byte token text can be borrowed.
+static BYTES: [u8; 256] = {
+    let mut bytes = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        bytes[i] = i as u8;
+        i += 1;
+    }
+    bytes
+};
+
+/// Copies the text of a token, except for single bytes and CRLF, which
+/// are borrowed, so that most tokens are not allocated.
+#[inline]
+fn token_text(text: &[u8]) -> Cow<'static, [u8]> {
+    match *text {
+        [b] => Cow::Borrowed(&BYTES[b as usize..b as usize + 1]),
+        [b'\r', b'\n'] => Cow::Borrowed(b"\r\n"),
+        _ => Cow::Owned(text.to_vec()),
+    }
+}
+
+impl<R: Read> Remainder for ReadLexer<R> {
+    fn remainder(&mut self) -> Result<Vec<u8>, LexError> {
+        // The last lexeme is discarded on the next call to `next`, so it
+        // remains when the parser stops before the lexer is done
+        let start = if self.done { self.start } else { self.i };
+        let mut rest = self.buf.split_off(start);
+        self.start = 0;
+        self.i = 0;
+        self.buf.clear();
+        self.r.read_to_end(&mut rest).map_err(|err| LexError {
+            kind: LexErrorKind::Io(err.kind()),
+            pos: self.pos,
+        })?;
+        Ok(rest)
+    }
+}
+
+/// Matches tokens and decodes comment characters, independently of how
+/// the source is buffered.
+#[derive(Debug, Clone)]
+struct Scanner {
+    map: Mapping,
+    /// Encoded patterns, longest first
+    pats: Vec<(Token, Vec<u8>)>,
+    encoding: Encoding,
+    line_ending: LineEnding,
+}
+
+enum Scan {
+    /// Token and the size of its pattern in the source
+    Token(Token, usize),
+    /// Comment character and its size
+    Char(char, usize),
+    /// More source is needed to match a token or decode a character
+    Incomplete,
+    InvalidUtf8,
+}
+
+impl Scanner {
+    fn new(map: Mapping, opts: LexOptions) -> Self {
+        let mut pats = [S, T, L]
+            .iter()
+            .map(|&tok| (tok, map.encode(tok, opts.encoding).into_owned()))
+            .collect::<Vec<_>>();
+        pats.sort_by_key(|(_, pat)| Reverse(pat.len()));
+        Scanner {
+            map,
+            pats,
+            encoding: opts.encoding,
+            line_ending: opts.line_ending,
+        }
+    }
+
+    /// Scans the token or character at the start of `src`, preferring the
+    /// longest matching pattern. Unless `eof`, `src` may end partway
+    /// through a token or character.
+    fn scan(&self, src: &[u8], eof: bool) -> Scan {
+        for (tok, pat) in &self.pats {
+            if src.starts_with(pat) {
+                return Scan::Token(*tok, pat.len());
+            }
+            if !eof && pat.starts_with(src) {
+                return Scan::Incomplete;
+            }
+        }
+        let (ch, size) = match self.encoding {
+            // Lazily decode UTF-8
+            Encoding::Utf8 => match bstr::decode_utf8(src) {
+                (Some(ch), size) => (ch, size),
+                (None, 0) => return Scan::Incomplete,
+                (None, size) if !eof && size == src.len() => return Scan::Incomplete,
+                (None, _) => return Scan::InvalidUtf8,
+            },
+            Encoding::Bytes => match src.first() {
+                Some(&b) => (b as char, 1),
+                None => return Scan::Incomplete,
+            },
+        };
+        if ch == '\r' && self.map.l == "\n" && self.line_ending != LineEnding::Lf {
+            match src.get(1) {
+                None if !eof => return Scan::Incomplete,
+                Some(b'\n') => return Scan::Token(L, 2),
+                _ if self.line_ending == LineEnding::Cr => return Scan::Token(L, 1),
+                _ => {}
+            }
+        }
+        Scan::Char(ch, size)
+    }
+
+    /// Advances past a token spanning `size` bytes of source. Line breaks
+    /// lexed as L count as a single LF.
+    fn advance(&self, pos: &mut Pos, tok: Token, size: usize) {
+        let offset = pos.offset + size;
+        self.map
+            .pattern(tok)
+            .chars()
+            .for_each(|ch| pos.advance(ch, 0));
+        pos.offset = offset;
+    }
+}
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct LexError {
+    pub kind: LexErrorKind,
+    pub pos: Pos,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LexErrorKind {
+    InvalidUtf8,
+    Io(io::ErrorKind),
+}
+
+impl error::Error for LexError {}
+
+impl fmt::Display for LexError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at {}", self.kind, self.pos)
+    }
+}
+
+impl fmt::Display for LexErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LexErrorKind::InvalidUtf8 => write!(f, "invalid UTF-8"),
+            LexErrorKind::Io(kind) => write!(f, "{}", io::Error::from(*kind)),
+        }
+    }
+}
+
+/// Patterns spelling S, T, and L. Patterns are non-empty, distinct
+/// strings and are lexed by longest match, so one may be a prefix of
+/// another.
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Mapping {
-    s: char,
-    t: char,
-    l: char,
+    s: Cow<'static, str>,
+    t: Cow<'static, str>,
+    l: Cow<'static, str>,
 }
 
 impl Mapping {
-    #[inline]
     #[must_use]
-    pub const fn new(s: char, t: char, l: char) -> Option<Self> {
-        if s == t || t == l || s == l {
+    pub fn new<S, T, L>(s: S, t: T, l: L) -> Option<Self>
+    where
+        S: Into<Cow<'static, str>>,
+        T: Into<Cow<'static, str>>,
+        L: Into<Cow<'static, str>>,
+    {
+        let (s, t, l) = (s.into(), t.into(), l.into());
+        if s.is_empty() || t.is_empty() || l.is_empty() || s == t || t == l || s == l {
             return None;
         }
         Some(Mapping { s, t, l })
@@ -69,27 +551,41 @@ impl Mapping {
 
     #[inline]
     #[must_use]
-    pub const fn from_char(&self, ch: char) -> Option<Token> {
-        if ch == self.s {
-            Some(S)
-        } else if ch == self.t {
-            Some(T)
-        } else if ch == self.l {
-            Some(L)
-        } else {
-            None
+    pub fn pattern(&self, tok: Token) -> &str {
+        match tok {
+            S => &self.s,
+            T => &self.t,
+            L => &self.l,
         }
     }
 
-    #[inline]
+    /// Encodes the pattern for a token. With `Encoding::Bytes`, each
+    /// character is a Latin-1 byte.
     #[must_use]
-    pub const fn to_char(&self, tok: Token) -> char {
-        match tok {
-            S => self.s,
-            T => self.t,
-            L => self.l,
+    pub fn encode(&self, tok: Token, encoding: Encoding) -> Cow<'_, [u8]> {
+        let pat = self.pattern(tok);
+        match encoding {
+            Encoding::Utf8 => Cow::Borrowed(pat.as_bytes()),
+            Encoding::Bytes => Cow::Owned(pat.chars().map(|ch| ch as u8).collect()),
         }
     }
+
+    /// Reports whether all characters are in the Latin-1 range, so the
+    /// mapping can match raw bytes.
+    #[must_use]
+    pub fn is_latin1(&self) -> bool {
+        [&self.s, &self.t, &self.l]
+            .iter()
+            .all(|pat| pat.chars().all(|ch| ch as u32 <= 0xff))
+    }
+
+    /// Reports whether each pattern is a single character.
+    #[must_use]
+    pub fn is_chars(&self) -> bool {
+        [&self.s, &self.t, &self.l]
+            .iter()
+            .all(|pat| pat.chars().count() == 1)
+    }
 }
 
 impl const Default for Mapping {
@@ -97,39 +593,107 @@ impl const Default for Mapping {
     #[must_use]
     fn default() -> Self {
         Mapping {
-            s: ' ',
-            t: '\t',
-            l: '\n',
+            s: Cow::Borrowed(" "),
+            t: Cow::Borrowed("\t"),
+            l: Cow::Borrowed("\n"),
         }
     }
 }
 
+/// Formats the mapping as three characters, when each pattern is a
+/// single character, or otherwise as comma-separated patterns with `\`,
+/// `,`, and line breaks escaped. The alternate form `{:#}` always uses
+/// escaped patterns. Either can be parsed with `FromStr`.
 impl fmt::Display for Mapping {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}{}{}", self.s, self.t, self.l)
+        if self.is_chars() && !f.alternate() {
+            return write!(f, "{}{}{}", self.s, self.t, self.l);
+        }
+        for (i, pat) in [&self.s, &self.t, &self.l].iter().enumerate() {
+            if i != 0 {
+                write!(f, ",")?;
+            }
+            for ch in pat.chars() {
+                match ch {
+                    '\\' => write!(f, "\\\\")?,
+                    ',' => write!(f, "\\,")?,
+                    '\t' => write!(f, "\\t")?,
+                    '\n' => write!(f, "\\n")?,
+                    '\r' => write!(f, "\\r")?,
+                    _ => write!(f, "{}", ch)?,
+                }
+            }
+        }
+        Ok(())
     }
 }
 
+/// Parses a mapping as either exactly three characters, like `" \t\n"`
+/// or `STL`, or as three comma-separated patterns, like `Ook.,Ook!,Ook?`.
+/// Patterns may contain the escapes `\\`, `\,`, `\t`, `\n`, `\r`, and
+/// `\u{XXXX}`.
 impl str::FromStr for Mapping {
     type Err = MappingFromStrError;
 
     fn from_str(v: &str) -> Result<Self, Self::Err> {
         use MappingFromStrError::*;
         let mut chars = v.chars();
-        let s = chars.next().ok_or(NotThreeChars)?;
-        let t = chars.next().ok_or(NotThreeChars)?;
-        let l = chars.next().ok_or(NotThreeChars)?;
-        if chars.as_str().len() != 0 {
-            return Err(NotThreeChars);
+        if let (Some(s), Some(t), Some(l), None) =
+            (chars.next(), chars.next(), chars.next(), chars.next())
+        {
+            return Mapping::new(String::from(s), String::from(t), String::from(l))
+                .ok_or(EqualPatterns);
+        }
+        let mut pats = Vec::new();
+        let mut pat = String::new();
+        let mut chars = v.chars();
+        while let Some(ch) = chars.next() {
+            match ch {
+                ',' => pats.push(mem::take(&mut pat)),
+                '\\' => pat.push(unescape(&mut chars)?),
+                _ => pat.push(ch),
+            }
+        }
+        pats.push(pat);
+        let [s, t, l]: [String; 3] = pats.try_into().map_err(|_| NotThreePatterns)?;
+        if s.is_empty() || t.is_empty() || l.is_empty() {
+            return Err(EmptyPattern);
+        }
+        Mapping::new(s, t, l).ok_or(EqualPatterns)
+    }
+}
+
+/// Parses the escape following a `\` in a mapping pattern.
+fn unescape(chars: &mut str::Chars<'_>) -> Result<char, MappingFromStrError> {
+    match chars.next() {
+        Some(ch @ ('\\' | ',')) => Ok(ch),
+        Some('t') => Ok('\t'),
+        Some('n') => Ok('\n'),
+        Some('r') => Ok('\r'),
+        Some('u') => {
+            let rest = chars.as_str();
+            let hex = rest
+                .strip_prefix('{')
+                .and_then(|rest| rest.split_once('}'))
+                .map(|(hex, _)| hex)
+                .ok_or(MappingFromStrError::InvalidEscape)?;
+            let ch = u32::from_str_radix(hex, 16)
+                .ok()
+                .and_then(char::from_u32)
+                .ok_or(MappingFromStrError::InvalidEscape)?;
+            *chars = rest[hex.len() + 2..].chars();
+            Ok(ch)
         }
-        Mapping::new(s, t, l).ok_or(EqualChars)
+        _ => Err(MappingFromStrError::InvalidEscape),
     }
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum MappingFromStrError {
-    EqualChars,
-    NotThreeChars,
+    EqualPatterns,
+    EmptyPattern,
+    NotThreePatterns,
+    InvalidEscape,
 }
 
 impl error::Error for MappingFromStrError {}
@@ -138,8 +702,13 @@ impl fmt::Display for MappingFromStrError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         use MappingFromStrError::*;
         match self {
-            EqualChars => write!(f, "characters in mapping are not unique"),
-            NotThreeChars => write!(f, "mapping is not exactly three characters"),
+            EqualPatterns => write!(f, "patterns in mapping are not unique"),
+            EmptyPattern => write!(f, "pattern in mapping is empty"),
+            NotThreePatterns => write!(
+                f,
+                "mapping is not three characters or three comma-separated patterns"
+            ),
+            InvalidEscape => write!(f, "invalid escape in mapping"),
         }
     }
 }
@@ -161,11 +730,199 @@ pub mod test {
     #[test]
     fn lex_tutorial() {
         let tokens = Lexer::new(&TUTORIAL_SRC, Mapping::default())
-            .map(|(tok, comment)| {
-                assert!(comment.len() == 0);
-                tok
+            .map(|lex| {
+                let lex = lex.unwrap();
+                assert!(lex.comment.len() == 0);
+                lex.tok
             })
             .collect::<Vec<_>>();
         assert_eq!(tokens, TUTORIAL_TOKENS);
     }
+
+    #[test]
+    fn lex_positions() {
+        let src = "a \tb\nc\u{e9}\n";
+        let lexemes = Lexer::new(&src, Mapping::default())
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let spans = lexemes
+            .iter()
+            .map(|lex| (lex.tok, str::from_utf8(&lex.comment).unwrap(), lex.span))
+            .collect::<Vec<_>>();
+        let pos = |offset, line, col| Pos { offset, line, col };
+        assert_eq!(
+            spans,
+            &[
+                (S, "a", Span::new(pos(1, 1, 2), pos(2, 1, 3))),
+                (T, "", Span::new(pos(2, 1, 3), pos(3, 1, 4))),
+                (L, "b", Span::new(pos(4, 1, 5), pos(5, 2, 1))),
+                (L, "c\u{e9}", Span::new(pos(8, 2, 3), pos(9, 3, 1))),
+            ]
+        );
+    }
+
+    #[test]
+    fn lex_invalid_utf8() {
+        let src = include_bytes!("../tests/encoding_invalid_utf8_lazy.ws");
+        let err = LexError {
+            kind: LexErrorKind::InvalidUtf8,
+            pos: Pos {
+                offset: 11,
+                line: 3,
+                col: 3,
+            },
+        };
+        let mut lex = Lexer::new(src, Mapping::default());
+        assert_eq!(lex.by_ref().filter_map(Result::ok).count(), 11);
+        assert_eq!(lex.next(), None);
+        let lex = Lexer::new(src, Mapping::default());
+        assert_eq!(lex.filter_map(Result::err).next(), Some(err));
+        let opts = LexOptions {
+            utf8_check: Utf8Check::Eager,
+            ..LexOptions::default()
+        };
+        let mut lex = Lexer::with_options(src, Mapping::default(), opts);
+        assert_eq!(lex.next(), Some(Err(err)));
+        assert_eq!(lex.next(), None);
+    }
+
+    #[test]
+    fn lex_bytes() {
+        let src = b"\xa0\xff\xa0\x00\x80";
+        let opts = LexOptions {
+            encoding: Encoding::Bytes,
+            ..LexOptions::default()
+        };
+        let map = Mapping::new("\u{a0}", "\u{80}", "\u{ff}").unwrap();
+        let lexemes = Lexer::with_options(src, map, opts)
+            .map(|lex| lex.map(|lex| (lex.tok, lex.comment.into_owned())))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(
+            lexemes,
+            &[(S, vec![]), (L, vec![]), (S, vec![]), (T, vec![0])]
+        );
+    }
+
+    #[test]
+    fn lex_line_endings() {
+        let src = "\r\n \r\t\r";
+        let lex = |line_ending| {
+            let opts = LexOptions {
+                line_ending,
+                ..LexOptions::default()
+            };
+            Lexer::with_options(&src, Mapping::default(), opts)
+                .map(|lex| lex.unwrap())
+                .map(|lex| {
+                    let (start, end) = (lex.span.start.offset, lex.span.end.offset);
+                    (lex.tok, start..end, lex.comment.into_owned())
+                })
+                .collect::<Vec<_>>()
+        };
+        // A CR outside of CRLF is a comment under LF rules
+        assert_eq!(
+            lex(LineEnding::Lf),
+            &[
+                (L, 1..2, b"\r".to_vec()),
+                (S, 2..3, vec![]),
+                (T, 4..5, b"\r".to_vec()),
+            ]
+        );
+        assert_eq!(
+            lex(LineEnding::Crlf),
+            &[
+                (L, 0..2, vec![]),
+                (S, 2..3, vec![]),
+                (T, 4..5, b"\r".to_vec()),
+            ]
+        );
+        assert_eq!(
+            lex(LineEnding::Cr),
+            &[
+                (L, 0..2, vec![]),
+                (S, 2..3, vec![]),
+                (L, 3..4, vec![]),
+                (T, 4..5, vec![]),
+                (L, 5..6, vec![]),
+            ]
+        );
+    }
+
+    #[test]
+    fn read_lex_split() {
+        // Reads a byte at a time to split characters across reads
+        struct ByteReader<'a>(&'a [u8]);
+        impl Read for ByteReader<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                let n = self.0.len().min(buf.len()).min(1);
+                buf[..n].copy_from_slice(&self.0[..n]);
+                self.0 = &self.0[n..];
+                Ok(n)
+            }
+        }
+        let src = b"\xf0\x9f\x98\x80 \r\n\xc3\xa9\t\r\r \xff";
+        let opts = LexOptions {
+            line_ending: LineEnding::Cr,
+            ..LexOptions::default()
+        };
+        let expect = Lexer::with_options(&src, Mapping::default(), opts).collect::<Vec<_>>();
+        let lex = ReadLexer::new(ByteReader(src), Mapping::default(), opts);
+        assert_eq!(lex.collect::<Vec<_>>(), expect);
+        let src = b"\xf0\x9f\x98 \t";
+        let expect = Lexer::with_options(&src, Mapping::default(), opts).collect::<Vec<_>>();
+        let lex = ReadLexer::new(ByteReader(src), Mapping::default(), opts);
+        assert_eq!(lex.collect::<Vec<_>>(), expect);
+    }
+
+    #[test]
+    fn lex_patterns() {
+        let map = "Ook.,Ook!,Ook?".parse::<Mapping>().unwrap();
+        let src = "Ook. Ook! Ook Ook?Ook.";
+        let lexemes = Lexer::new(&src, map.clone())
+            .map(|lex| {
+                let lex = lex.unwrap();
+                (lex.tok, lex.comment.into_owned(), lex.span.end.col)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            lexemes,
+            &[
+                (S, b"".to_vec(), 5),
+                (T, b" ".to_vec(), 10),
+                (L, b" Ook ".to_vec(), 19),
+                (S, b"".to_vec(), 23),
+            ]
+        );
+        let expect = Lexer::new(&src, map.clone()).collect::<Vec<_>>();
+        let lex = ReadLexer::new(src.as_bytes(), map, LexOptions::default());
+        assert_eq!(lex.collect::<Vec<_>>(), expect);
+
+        let map = "a\\,,ab,\\u{200b}\\n".parse::<Mapping>().unwrap();
+        assert_eq!(map.pattern(S), "a,");
+        assert_eq!(map.pattern(L), "\u{200b}\n");
+        assert_eq!(map.to_string().parse::<Mapping>(), Ok(map));
+        assert_eq!(format!("{:#}", Mapping::default()), " ,\\t,\\n");
+        assert_eq!("abc".parse::<Mapping>().unwrap().pattern(T), "b");
+        assert_eq!(
+            "a,b".parse::<Mapping>(),
+            Mapping::new("a", ",", "b").ok_or(MappingFromStrError::EqualPatterns)
+        );
+        assert_eq!(
+            "ab,ab,c".parse::<Mapping>(),
+            Err(MappingFromStrError::EqualPatterns)
+        );
+        assert_eq!(
+            "ab,,c".parse::<Mapping>(),
+            Err(MappingFromStrError::EmptyPattern)
+        );
+        assert_eq!(
+            "ab,c".parse::<Mapping>(),
+            Err(MappingFromStrError::NotThreePatterns)
+        );
+        assert_eq!(
+            "ab,c,\\q".parse::<Mapping>(),
+            Err(MappingFromStrError::InvalidEscape)
+        );
+    }
 }